@@ -0,0 +1,16 @@
+// shared by fwtb and fwtb-tray, which both switch fwtb-ab on and off
+
+use std::process::Command;
+
+// fwtb-ab runs as a user service, see fwtb-ab.service
+pub fn backlight_service(action: &str) {
+    let mut systemctl = Command::new("systemctl");
+    systemctl.args(["--user", action]);
+    if action != "start" {
+        systemctl.arg("--now");
+    }
+    match systemctl.arg("fwtb-ab.service").status() {
+        Ok(status) if status.success() => {}
+        _ => eprintln!("couldn't {} fwtb-ab.service", action),
+    }
+}
@@ -1,115 +1,1348 @@
-use anyhow::Result;
+#[path = "../config_watch.rs"]
+mod config_watch;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Datelike, Local, Timelike};
+use serde::{Deserialize, Serialize};
 use splines::{Interpolation, Key, Spline};
 use tokio::{
-    fs::{read_to_string, write},
-    join, spawn,
+    fs::{create_dir_all, read_dir, read_to_string, remove_file, write, File},
+    io::{unix::AsyncFd, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    select,
+    signal::unix::{signal, SignalKind},
+    spawn,
+    sync::{mpsc, oneshot, watch, Notify},
+    task::spawn_blocking,
     task::JoinHandle,
     time::sleep,
 };
+use toml_edit::easy::value::Table;
+
+use config_watch::ConfigWatch;
 
 use std::{
     collections::VecDeque,
-    sync::{atomic::AtomicU32, atomic::Ordering, Arc},
-    time::Duration,
+    io::{Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::{
+            fs::OpenOptionsExt,
+            net::{SocketAddr, UnixDatagram},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-const SAMPLE_SIZE: u32 = 100;
-const SAMPLE_INTERVAL_MS: u64 = 100;
 const FPS: u32 = 60;
+// exit status when another fwtb-ab holds the lock
+const ALREADY_RUNNING: i32 = 3;
 const TPF: u64 = 1000 / FPS as u64;
-// const HISTERESIS: u32 = 5000;
+// most preferred first, see sysfs-class-backlight(5) for what each means
+const BACKLIGHT_TYPES: [&str; 3] = ["raw", "firmware", "platform"];
+// where the Framework's sensor tops out, for when it can't be discovered or
+// configured. the curve stretches past it anyway if readings go higher
+const DEFAULT_MAX_LUX: f32 = 3355.;
+// from linux/input-event-codes.h
+const EV_SYN: u16 = 0;
+const EV_KEY: u16 = 1;
+const KEY_BRIGHTNESSDOWN: u16 = 224;
+const KEY_BRIGHTNESSUP: u16 = 225;
+// how often a change made by hand is looked at while it settles
+const SETTLE_TICK: Duration = Duration::from_millis(250);
+// struct input_event: timeval, then type, code and value at the end
+const INPUT_EVENT_SIZE: usize = std::mem::size_of::<libc::input_event>();
+// from linux/iio/events.h, _IOR('i', 0x90, int)
+const IIO_GET_EVENT_FD_IOCTL: u64 = 0x8004_6990;
+// struct iio_event_data: id, timestamp
+const IIO_EVENT_SIZE: usize = 16;
+// from linux/i2c-dev.h
+const I2C_SLAVE: u64 = 0x0703;
+// where monitors listen for DDC/CI, and the VCP code for their brightness
+const DDC_ADDR: u8 = 0x37;
+const VCP_BRIGHTNESS: u8 = 0x10;
+// readings within this fraction of the last, or a lux of it, count as steady
+const STEADY: f32 = 0.02;
+
+static STATS: Stats = Stats::new();
 
 #[tokio::main]
 async fn main() -> Result<()> {
     #[cfg(tokio_unstable)]
     console_subscriber::init();
 
-    let mut backlight = Backlight::new().await?;
-    let mut sensor = Sensor::new().await?;
-    let average = Arc::new(AtomicU32::new(Sensor::get().await?));
+    // `fwtb-ab ctl <command>` talks to the one that's already running
+    if std::env::args().nth(1).as_deref() == Some("ctl") {
+        let command = std::env::args().skip(2).collect::<Vec<_>>().join(" ");
+        print!("{}", send_command(&command).await?);
+        return Ok(());
+    }
+
+    STATS.start();
+    let config = Config::load().await?;
+    if config.replay.is_some() {
+        return replay(config, &mut std::io::stdout()).await;
+    }
+    // whoever started us, e.g. the service still being enabled from before.
+    // ready and done rather than failed, so systemd doesn't restart us
+    if !config.enabled {
+        eprintln!(
+            "backlight_auto is off in {}, not starting",
+            Config::path().display()
+        );
+        notify_ready();
+        return Ok(());
+    }
+    // held until we exit
+    let _lock = lock()?;
+    if config.reset_curve {
+        reset_curve(&config.curve).await?;
+    }
+    let logind = match config.backend {
+        Backend::Logind => Some(
+            zbus::Connection::system()
+                .await
+                .context("couldn't connect to the system bus for logind")?,
+        ),
+        Backend::Sysfs => None,
+    };
+    let mut sensor = Sensor::new(&config).await?;
+    let mut backlight = Backlight::new(&config, sensor.max, logind.clone()).await?;
+    let mut keyboard = if config.keyboard {
+        Some(Keyboard::new(&config, logind).await?)
+    } else {
+        None
+    };
+    let mut hysteresis = Hysteresis::new(&config, sensor.smoothed());
+    let interval = Duration::from_millis(config.sample_interval_ms);
+    let max_interval = Duration::from_millis(config.max_sample_interval_ms).max(interval);
+    // what's changed in fwtb.toml since, for each task to pick up
+    let (reconfigure, settings) = watch::channel(config.clone());
+    // adjust_retain puts the brightness back first, then main starts over
+    let restart_wanted = Arc::new(Notify::new());
+    let wanted = restart_wanted.clone();
+    let reload: JoinHandle<Result<()>> = spawn(async move {
+        let path = Config::path();
+        let mut watch = match ConfigWatch::new(&path) {
+            Ok(watch) => watch,
+            Err(e) => {
+                eprintln!("can't watch {} for changes: {:#}", path.display(), e);
+                return Ok(());
+            }
+        };
+        loop {
+            watch.changed().await?;
+            // let whatever's writing it finish
+            nap(Duration::from_millis(100)).await;
+            let config = match Config::load()
+                .await
+                .and_then(|config| Schedule::new(&config).map(|_| config))
+            {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("not reloading: {:#}", e);
+                    continue;
+                }
+            };
+            if config.needs_restart(&reconfigure.borrow()) {
+                // don't trade a working setup for one that won't start
+                let found = async {
+                    Sensor::find(&config).await?;
+                    Backlight::find(&config).await?;
+                    if config.keyboard {
+                        Keyboard::find(&config).await?;
+                    }
+                    anyhow::Ok(())
+                };
+                if let Err(e) = found.await {
+                    eprintln!("not restarting for the new settings: {:#}", e);
+                    continue;
+                }
+                eprintln!("restarting for the new settings");
+                wanted.notify_one();
+                return Ok(());
+            }
+            reconfigure.send_replace(config);
+        }
+    });
+    // lux as f32 bits, there's no AtomicF32
+    let average = Arc::new(AtomicU32::new(sensor.smoothed().to_bits()));
+
+    let screen = Screen {
+        sysfs_root: config.sysfs_root.clone(),
+        proc_root: config.proc_root.clone(),
+        backlight: backlight.path.clone(),
+        logind: backlight.logind.clone(),
+    };
+    // lid shut or screen off, checked in its own task so nothing else has to
+    let asleep = Arc::new(AtomicBool::new(screen.off().await));
+
+    let off = asleep.clone();
+    let watch_screen: JoinHandle<Result<()>> = spawn(async move {
+        loop {
+            off.store(screen.off().await, Ordering::Relaxed);
+            nap(interval * 10).await;
+        }
+    });
+
+    // what the adjust loop's going for and at, for recording
+    let levels = Arc::new(Levels {
+        target: AtomicU32::new(backlight.target),
+        brightness: AtomicU32::new(backlight.requested),
+    });
+    let mut trace = match &config.record {
+        Some(path) => Some(Trace::create(path, backlight.mapping.max as u32).await?),
+        None => None,
+    };
 
     let avg = average.clone();
+    let off = asleep.clone();
+    let shown = levels.clone();
+    let mut sensor_settings = settings.clone();
     let sample: JoinHandle<Result<()>> = spawn(async move {
+        let mut wait = interval;
         loop {
-            sensor.sample().await?;
-            avg.store(
-                sensor.samples.iter().sum::<u32>() / SAMPLE_SIZE,
-                Ordering::Relaxed,
-            );
-            sleep(Duration::from_millis(SAMPLE_INTERVAL_MS)).await;
+            if sensor_settings.has_changed().unwrap_or(false) {
+                sensor.configure(&sensor_settings.borrow_and_update());
+            }
+            if off.load(Ordering::Relaxed) {
+                nap(interval * 10).await;
+                continue;
+            }
+            let steady = sensor.sample().await?;
+            avg.store(sensor.smoothed().to_bits(), Ordering::Relaxed);
+            if let Some(trace) = &mut trace {
+                trace
+                    .write(
+                        sensor.last,
+                        shown.target.load(Ordering::Relaxed),
+                        shown.brightness.load(Ordering::Relaxed),
+                    )
+                    .await?;
+            }
+            // back off while the light holds steady, and catch up once it doesn't
+            wait = if steady {
+                (wait * 2).min(max_interval)
+            } else {
+                interval
+            };
+            if wait == max_interval && sensor.events.is_some() {
+                sensor.wait(max_interval).await?;
+            } else {
+                nap(wait).await;
+            }
+        }
+    });
+
+    // ddc is slow and blocking, so it gets a thread of its own
+    let mut monitors = if config.ddc {
+        Monitor::all(&config).await
+    } else {
+        Vec::new()
+    };
+    let max = backlight.mapping.max;
+    let off = asleep.clone();
+    let shown = levels.clone();
+    // dropped to stop it, shutdown waits for blocking threads
+    let (stop_ddc, stopped) = std::sync::mpsc::channel::<()>();
+    let ddc: JoinHandle<Result<()>> = spawn_blocking(move || {
+        if monitors.is_empty() {
+            return Ok(());
+        }
+        loop {
+            if !off.load(Ordering::Relaxed) {
+                let fraction = shown.target.load(Ordering::Relaxed) as f32 / max;
+                for monitor in &mut monitors {
+                    monitor.update(fraction);
+                }
+            }
+            if let Err(RecvTimeoutError::Disconnected) = stopped.recv_timeout(interval * 10) {
+                return Ok(());
+            }
+            STATS.wakeups.fetch_add(1, Ordering::Relaxed);
         }
     });
 
+    let input = Arc::new(Input::new());
+    // everything for idle timeouts, otherwise only the brightness keys
+    let everything = config.everything();
+    let mut watching = 0;
+    if config.brightness_keys || everything {
+        watching = watch_input(&config, input.clone(), everything).await;
+    }
+    if watching == 0 && (config.brightness_keys || everything) {
+        eprintln!("can't read any input devices, only watching the backlight for changes");
+    }
+    let mut pause = Duration::from_secs(config.pause_minutes * 60);
+    let mut idle_dim = Duration::from_secs(config.idle_dim_secs);
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let (commands, mut requests) = mpsc::channel(8);
+    let control: JoinHandle<Result<()>> = spawn(listen(commands));
+
     let avg = average.clone();
-    let adjust_retain: JoinHandle<Result<()>> = spawn(async move {
+    let activity = input.clone();
+    let off = asleep.clone();
+    let mut backlight_settings = settings.clone();
+    // true if it stopped to restart
+    let adjust_retain: JoinHandle<Result<bool>> = spawn(async move {
+        let keys = &activity.brightness_keys;
+        let mut was_off = false;
+        let mut settling: Option<Settling> = None;
+        let mut restarting = false;
         loop {
-            if !backlight.changed().await? {
-                let d;
-                if Backlight::get().await? != backlight.target {
-                    backlight.adjust().await?;
-                    d = Duration::from_millis(TPF);
+            if backlight_settings.has_changed().unwrap_or(false) {
+                let config = backlight_settings.borrow_and_update().clone();
+                backlight.configure(&config).await?;
+                hysteresis.configure(&config);
+                pause = Duration::from_secs(config.pause_minutes * 60);
+                idle_dim = Duration::from_secs(config.idle_dim_secs);
+            }
+            let d = if let Some(settle) = &mut settling {
+                // a pass at a time rather than waiting it out here, so commands
+                // and signals still get answered meanwhile
+                let now = backlight.get().await?;
+                if now != settle.brightness || keys.swap(false, Ordering::Relaxed) {
+                    settle.brightness = now;
+                    settle.stable = Duration::ZERO;
+                }
+                if settle.stable >= backlight.settle {
+                    backlight.retain(settle.lux, settle.brightness).await?;
+                    if !pause.is_zero() {
+                        backlight.paused_until = Some(Instant::now() + pause);
+                    }
+                    settling = None;
+                    Duration::ZERO
                 } else {
-                    d = Duration::from_millis(SAMPLE_INTERVAL_MS * 10);
+                    settle.stable += SETTLE_TICK;
+                    SETTLE_TICK
                 }
-                backlight.prepare(avg.load(Ordering::Relaxed)).await?;
-                sleep(d).await;
+            } else if off.load(Ordering::Relaxed) {
+                was_off = true;
+                interval * 10
             } else {
-                backlight.retain(avg.load(Ordering::Relaxed)).await?;
+                if was_off {
+                    // whatever happened to it while off wasn't someone adjusting it
+                    was_off = false;
+                    backlight.requested = backlight.get().await?;
+                    keys.store(false, Ordering::Relaxed);
+                }
+
+                backlight.dimmed =
+                    !idle_dim.is_zero() && watching > 0 && activity.idle() >= idle_dim;
+                let pressed = keys.swap(false, Ordering::Relaxed);
+                // read once for both, it adds up
+                let current = backlight.get().await?;
+                if !pressed && current == backlight.requested {
+                    let d;
+                    if !backlight.paused() && current != backlight.target {
+                        backlight.adjust().await?;
+                        d = Duration::from_millis(TPF);
+                    } else {
+                        d = interval * 10;
+                    }
+                    let lux = hysteresis.update(f32::from_bits(avg.load(Ordering::Relaxed)));
+                    backlight.prepare(lux).await?;
+                    levels.target.store(backlight.target, Ordering::Relaxed);
+                    levels.brightness.store(current, Ordering::Relaxed);
+                    d
+                } else {
+                    // learn at what the light is now, not what we were holding on to
+                    let lux = f32::from_bits(avg.load(Ordering::Relaxed));
+                    hysteresis.lux = lux;
+                    settling = Some(Settling {
+                        lux,
+                        brightness: current,
+                        stable: Duration::ZERO,
+                    });
+                    Duration::ZERO
+                }
+            };
+
+            // answer right away rather than after however long we'd sleep. each
+            // pass is only a few reads and writes, nothing in it waits
+            STATS.wakeups.fetch_add(1, Ordering::Relaxed);
+            select! {
+                _ = sleep(d) => {}
+                Some((command, reply)) = requests.recv() => {
+                    let _ = reply.send(backlight.command(command, hysteresis.lux).await);
+                }
+                _ = terminate.recv() => break,
+                _ = interrupt.recv() => break,
+                _ = restart_wanted.notified() => {
+                    restarting = true;
+                    break;
+                }
+            }
+        }
+
+        // don't leave it dimmed for idle, or partway through a ramp
+        if !off.load(Ordering::Relaxed) && !backlight.paused() {
+            backlight.dimmed = false;
+            backlight.prepare(hysteresis.lux).await?;
+            let target = backlight.target;
+            backlight.set(target).await?;
+        }
+        Ok(restarting)
+    });
+
+    let avg = average.clone();
+    let mut keyboard_settings = settings;
+    let keyboard: JoinHandle<Result<()>> = spawn(async move {
+        let Some(keyboard) = &mut keyboard else {
+            return Ok(());
+        };
+        loop {
+            if keyboard_settings.has_changed().unwrap_or(false) {
+                let config = keyboard_settings.borrow_and_update().clone();
+                keyboard.configure(&config).await?;
             }
+            if asleep.load(Ordering::Relaxed) {
+                nap(interval * 10).await;
+                continue;
+            }
+            let lux = f32::from_bits(avg.load(Ordering::Relaxed));
+            // can't tell it's idle if we can't see any input
+            let idle = if watching > 0 {
+                input.idle()
+            } else {
+                Duration::ZERO
+            };
+            keyboard.update(lux, idle).await?;
+            nap(interval * 5).await;
         }
     });
 
-    let _ = join![sample, adjust_retain];
-    unreachable!()
+    notify_ready();
+
+    // the rest only stop if they fail, adjust_retain also when told to
+    let done = select! {
+        done = adjust_retain => done?,
+        Ok(Err(e)) = watch_screen => Err(e),
+        Ok(Err(e)) = sample => Err(e),
+        Ok(Err(e)) = control => Err(e),
+        Ok(Err(e)) = keyboard => Err(e),
+        Ok(Err(e)) = ddc => Err(e),
+        Ok(Err(e)) = reload => Err(e),
+    };
+    drop(stop_ddc);
+    if let Some(path) = socket_path() {
+        let _ = remove_file(path).await;
+    }
+    match done {
+        // with the brightness put back, start over with the new settings
+        Ok(true) => Err(restart()),
+        done => done.map(|_| ()),
+    }
+}
+
+struct Levels {
+    target: AtomicU32,
+    brightness: AtomicU32,
+}
+
+// a change made by hand, looked at every SETTLE_TICK until it's stopped
+// changing for settle_ms and can be learned
+struct Settling {
+    // what the light was when it started
+    lux: f32,
+    brightness: u32,
+    stable: Duration,
+}
+
+// timestamped readings as `ms,lux,target,brightness` lines, what --replay reads
+struct Trace {
+    file: File,
+    start: Instant,
+}
+
+impl Trace {
+    async fn create(path: &Path, max_brightness: u32) -> Result<Self> {
+        let mut file = File::create(path)
+            .await
+            .with_context(|| format!("couldn't create {}", path.display()))?;
+        let header = format!(
+            "# max_brightness {}\nms,lux,target,brightness\n",
+            max_brightness
+        );
+        file.write_all(header.as_bytes()).await?;
+        Ok(Self {
+            file,
+            start: Instant::now(),
+        })
+    }
+
+    async fn write(&mut self, lux: f32, target: u32, brightness: u32) -> Result<()> {
+        let ms = self.start.elapsed().as_millis();
+        let line = format!("{},{},{},{}\n", ms, lux, target, brightness);
+        self.file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    // returns max_brightness, and ms, lux and brightness for each sample
+    async fn read(path: &Path) -> Result<(u32, Vec<(u64, f32, u32)>)> {
+        let s = read_to_string(path)
+            .await
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        let mut max_brightness = 255;
+        let mut samples = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some(max) = comment.trim().strip_prefix("max_brightness ") {
+                    max_brightness = max.trim().parse()?;
+                }
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if line.trim().is_empty() || fields[0] == "ms" {
+                continue;
+            }
+            // replays add a column for each monitor
+            let [ms, lux, _, brightness, ..] = fields[..] else {
+                bail!(
+                    "line {} of {} isn't ms,lux,target,brightness",
+                    i + 1,
+                    path.display()
+                );
+            };
+            let parsed = (ms.parse(), lux.parse(), brightness.parse());
+            let (Ok(ms), Ok(lux), Ok(brightness)) = parsed else {
+                bail!(
+                    "line {} of {} has something that isn't a number",
+                    i + 1,
+                    path.display()
+                );
+            };
+            samples.push((ms, lux, brightness));
+        }
+        Ok((max_brightness, samples))
+    }
+}
+
+// feeds a trace through the same smoothing, hysteresis, curve and ramps
+// against a fake sysfs tree, and prints what the brightness would've done
+// as another trace. takes as long as the frames do, not as long as it took
+async fn replay(mut config: Config, out: &mut impl Write) -> Result<()> {
+    let path = config.replay.take().unwrap();
+    let (max_brightness, samples) = Trace::read(&path).await?;
+    let Some(&(_, first_lux, first_brightness)) = samples.first() else {
+        bail!("{} has no samples", path.display());
+    };
+
+    let root = std::env::temp_dir().join(format!("fwtb-replay-{}", std::process::id()));
+    let sensor_dir = root.join("bus/iio/devices/iio:device0");
+    let backlight_dir = root.join("class/backlight/replay");
+    create_dir_all(&sensor_dir).await?;
+    create_dir_all(&backlight_dir).await?;
+    write(
+        sensor_dir.join("in_illuminance_input"),
+        first_lux.to_string(),
+    )
+    .await?;
+    write(backlight_dir.join("type"), "raw").await?;
+    write(
+        backlight_dir.join("max_brightness"),
+        max_brightness.to_string(),
+    )
+    .await?;
+    write(
+        backlight_dir.join("brightness"),
+        first_brightness.to_string(),
+    )
+    .await?;
+    // starts from the learned curve, but doesn't learn into it
+    let curve = root.join("curve.toml");
+    if let Ok(saved) = read_to_string(&config.curve).await {
+        write(&curve, saved).await?;
+    }
+
+    config.sysfs_root = root.clone();
+    // there's no lid in the trace, and the real one's state doesn't matter
+    config.proc_root = root.clone();
+    config.device = Some(String::from("replay"));
+    config.sensor = None;
+    config.backend = Backend::Sysfs;
+    config.sampling = Sampling::Poll;
+    config.curve = curve;
+    // these go by the clock and battery now, not when it was recorded
+    config.night = false;
+    config.battery = false;
+
+    let replayed = async {
+        let mut sensor = Sensor::new(&config).await?;
+        let mut backlight = Backlight::new(&config, sensor.max, None).await?;
+        let mut hysteresis = Hysteresis::new(&config, sensor.smoothed());
+        // the configured monitors, or one with the default curve, as fakes
+        let mut monitors = Vec::new();
+        if config.ddc {
+            let mut configured = config.monitors.clone();
+            if configured.is_empty() {
+                configured.push(MonitorConfig {
+                    bus: String::from("fake"),
+                    curve: None,
+                });
+            }
+            for monitor in configured {
+                let ddc = Ddc {
+                    bus: Box::new(FakeMonitor::new(100)),
+                    wait: Duration::ZERO,
+                };
+                monitors.push(Monitor::new(monitor.bus, ddc, monitor.curve)?);
+            }
+        }
+        let start = Instant::now();
+        let frame = Duration::from_millis(TPF);
+        let mut now = Duration::ZERO;
+        writeln!(out, "# max_brightness {}", max_brightness)?;
+        let buses: String = monitors.iter().map(|m| format!(",{}", m.bus)).collect();
+        writeln!(out, "ms,lux,target,brightness{}", buses)?;
+        for (ms, lux, _) in samples {
+            let at = Duration::from_millis(ms);
+            // ramp frames up to this sample, same as the adjust loop would
+            while now + frame <= at && backlight.get().await? != backlight.target {
+                backlight.adjust().await?;
+                now += frame;
+            }
+            now = now.max(at);
+
+            write(sensor.path.join("in_illuminance_input"), lux.to_string()).await?;
+            sensor.sample().await?;
+            let held = hysteresis.update_at(sensor.smoothed(), start + at);
+            backlight.prepare(held).await?;
+            let brightness = backlight.get().await?;
+            let mut levels = String::new();
+            for monitor in &mut monitors {
+                monitor.update(backlight.target as f32 / backlight.mapping.max);
+                let level = monitor
+                    .ddc
+                    .get()
+                    .map(|(level, _)| level)
+                    .unwrap_or_default();
+                levels.push_str(&format!(",{}", level));
+            }
+            writeln!(
+                out,
+                "{},{},{},{}{}",
+                ms, lux, backlight.target, brightness, levels
+            )?;
+        }
+        Ok(())
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&root).await;
+    replayed
+}
+
+// starts over as a new process with the same arguments, only returns if it can't
+fn restart() -> anyhow::Error {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return e.into(),
+    };
+    // the curve's already been reset once
+    let args = std::env::args_os()
+        .skip(1)
+        .filter(|arg| arg.as_os_str() != "--reset-curve");
+    std::process::Command::new(exe).args(args).exec().into()
+}
+
+// there'd be two of us fighting over the brightness otherwise
+fn lock() -> Result<std::fs::File> {
+    let mut path = dirs::runtime_dir().unwrap_or_else(std::env::temp_dir);
+    path.push("fwtb");
+    std::fs::create_dir_all(&path)?;
+    path.push("fwtb-ab.lock");
+    let file = std::fs::File::create(&path)
+        .with_context(|| format!("couldn't open {}", path.display()))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        eprintln!("fwtb-ab is already running");
+        // restarting won't help, see RestartPreventExitStatus in fwtb-ab.service
+        std::process::exit(ALREADY_RUNNING);
+    }
+    Ok(file)
+}
+
+// tells systemd we're up when run as a Type=notify service
+fn notify_ready() {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Ok(socket) = UnixDatagram::unbound() else {
+        return;
+    };
+    let path = path.to_string_lossy();
+    // starting with @ means the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)
+        }
+        None => SocketAddr::from_pathname(path.as_ref()),
+    };
+    if let Err(e) = addr.and_then(|addr| socket.send_to_addr(b"READY=1", &addr)) {
+        eprintln!("couldn't notify systemd: {}", e);
+    }
+}
+
+// checked against Config as it's read, but kept as a table so
+// one file's settings can go over another's
+#[derive(Deserialize)]
+struct ConfigFile {
+    // the gui's switch for all of this
+    backlight_auto: Option<bool>,
+    #[serde(default, deserialize_with = "checked")]
+    backlight: Table,
+}
+
+fn checked<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Table, D::Error> {
+    let table = Table::deserialize(deserializer)?;
+    toml_edit::easy::Value::Table(table.clone())
+        .try_into::<Config>()
+        .map_err(serde::de::Error::custom)?;
+    Ok(table)
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+struct Config {
+    // lets us point at a fake tree instead of /sys
+    sysfs_root: PathBuf,
+    // and instead of /proc, for the lid
+    proc_root: PathBuf,
+    // name under /sys/class/backlight, otherwise picked by BACKLIGHT_TYPES
+    device: Option<String>,
+    // iio device `name`, otherwise the first one with an illuminance channel
+    sensor: Option<String>,
+    // brightest reading the sensor gives, if the driver doesn't say
+    max_lux: Option<f32>,
+    // interpolate and ramp in log lux and gamma encoded brightness
+    perceptual: bool,
+    gamma: f32,
+    smoothing: Smoothing,
+    // samples kept for mean and median
+    window: usize,
+    // weight given to each new sample by ema
+    ema_alpha: f32,
+    sample_interval_ms: u64,
+    // sample less often, up to this, while the light holds steady
+    max_sample_interval_ms: u64,
+    // "events" to sleep until the sensor says the light changed, if it can
+    sampling: Sampling,
+    // how much the light has to change, as a fraction of what we last acted
+    // on, and for how long before we follow it
+    brighten_threshold: f32,
+    brighten_delay_ms: u64,
+    dim_threshold: f32,
+    dim_delay_ms: u64,
+    // how long moving to a new target takes
+    ramp_ms: u64,
+    // how to set the brightness, see Backend
+    backend: Backend,
+    // watch brightness keys on top of the backlight itself to tell when it's
+    // being changed by hand
+    brightness_keys: bool,
+    // how long the brightness has to stay put before learning it
+    settle_ms: u64,
+    // leave the backlight alone for this long after it's changed by hand
+    pause_minutes: u64,
+    // drive the keyboard backlight too
+    keyboard: bool,
+    // name under /sys/class/leds, otherwise the first *kbd_backlight
+    keyboard_device: Option<String>,
+    // light up below on_lux, go dark above off_lux, leave it in between
+    keyboard_on_lux: f32,
+    keyboard_off_lux: f32,
+    // brightness when lit, otherwise its max
+    keyboard_level: Option<u32>,
+    // go dark after this long without input, 0 to never
+    keyboard_idle_secs: u64,
+    // dim the screen to idle_dim_factor of its target after this long without
+    // input, 0 to never
+    idle_dim_secs: u64,
+    idle_dim_factor: f32,
+    // scale the target by night_factor, and cap it at night_cap of the max,
+    // between night_start and night_end, fading in and out over fade_minutes
+    night: bool,
+    night_start: TimeOfDay,
+    night_end: TimeOfDay,
+    night_fade_minutes: f64,
+    night_factor: f32,
+    night_cap: Option<f32>,
+    // where we are, for sunrise and sunset
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    // scale the target by battery_factor, and cap it at battery_cap of the
+    // max, while unplugged at or below battery_threshold percent
+    battery: bool,
+    battery_threshold: u32,
+    battery_factor: f32,
+    battery_cap: Option<f32>,
+    // follow along on external monitors over DDC/CI, needs the i2c group
+    ddc: bool,
+    // which, by i2c bus, otherwise every connected one
+    monitors: Vec<MonitorConfig>,
+    // write `ms,lux,target,brightness` lines here as samples come in
+    record: Option<PathBuf>,
+    // where the curve's learned, usually curve_path()
+    #[serde(skip)]
+    curve: PathBuf,
+    #[serde(skip)]
+    reset_curve: bool,
+    // a recorded trace to run through offline instead
+    #[serde(skip)]
+    replay: Option<PathBuf>,
+    // backlight_auto, outside the backlight table
+    #[serde(skip)]
+    enabled: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            sysfs_root: PathBuf::from("/sys"),
+            proc_root: PathBuf::from("/proc"),
+            device: None,
+            sensor: None,
+            max_lux: None,
+            perceptual: false,
+            gamma: 2.2,
+            smoothing: Smoothing::Mean,
+            window: 100,
+            ema_alpha: 0.05,
+            sample_interval_ms: 100,
+            max_sample_interval_ms: 1000,
+            sampling: Sampling::Poll,
+            brighten_threshold: 0.1,
+            brighten_delay_ms: 2000,
+            dim_threshold: 0.2,
+            dim_delay_ms: 5000,
+            ramp_ms: 1000,
+            backend: Backend::Sysfs,
+            brightness_keys: true,
+            settle_ms: 2000,
+            pause_minutes: 0,
+            keyboard: false,
+            keyboard_device: None,
+            keyboard_on_lux: 5.,
+            keyboard_off_lux: 20.,
+            keyboard_level: None,
+            keyboard_idle_secs: 30,
+            idle_dim_secs: 0,
+            idle_dim_factor: 0.5,
+            night: false,
+            night_start: TimeOfDay::Sunset,
+            night_end: TimeOfDay::Sunrise,
+            night_fade_minutes: 60.,
+            night_factor: 0.7,
+            night_cap: None,
+            latitude: None,
+            longitude: None,
+            battery: false,
+            battery_threshold: 30,
+            battery_factor: 0.8,
+            battery_cap: None,
+            ddc: false,
+            monitors: Vec::new(),
+            record: None,
+            curve: curve_path(),
+            reset_curve: false,
+            replay: None,
+            enabled: true,
+        }
+    }
+}
+
+impl Config {
+    // shares fwtb.toml with the gui, under [backlight]
+    fn path() -> PathBuf {
+        let mut conf = dirs::config_dir().unwrap();
+        conf.push("fwtb.toml");
+        conf
+    }
+
+    // idle timeouts need every input device watched, not just the brightness keys
+    fn everything(&self) -> bool {
+        (self.keyboard && self.keyboard_idle_secs > 0) || self.idle_dim_secs > 0
+    }
+
+    // settings that only take effect on a restart
+    fn needs_restart(&self, other: &Self) -> bool {
+        self.setup() != other.setup()
+    }
+
+    // what everything's set up around, anything else can change as it runs
+    fn setup(&self) -> impl PartialEq + '_ {
+        (
+            (&self.sysfs_root, &self.proc_root),
+            (&self.device, &self.sensor, self.max_lux),
+            (self.sample_interval_ms, self.max_sample_interval_ms),
+            (self.sampling, self.backend, self.brightness_keys),
+            (self.everything(), self.keyboard, &self.keyboard_device),
+            (self.ddc, &self.monitors, &self.record, &self.curve),
+            self.enabled,
+        )
+    }
+
+    async fn load() -> Result<Self> {
+        // the user's settings over the system-wide ones, key by key
+        let mut settings = Table::new();
+        let mut enabled = true;
+        for conf in [PathBuf::from("/etc/fwtb/config.toml"), Self::path()] {
+            let Ok(s) = read_to_string(&conf).await else {
+                continue;
+            };
+            let mut file = toml_edit::easy::from_str::<ConfigFile>(&s)
+                .with_context(|| format!("couldn't parse {}", conf.display()))?;
+            settings.extend(std::mem::take(&mut file.backlight));
+            enabled = file.backlight_auto.unwrap_or(enabled);
+        }
+        let mut config: Config = toml_edit::easy::Value::Table(settings).try_into()?;
+        config.enabled = enabled;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--sysfs-root" => {
+                    config.sysfs_root = args.next().context("--sysfs-root needs a path")?.into()
+                }
+                "--proc-root" => {
+                    config.proc_root = args.next().context("--proc-root needs a path")?.into()
+                }
+                "--device" => config.device = Some(args.next().context("--device needs a name")?),
+                "--sensor" => config.sensor = Some(args.next().context("--sensor needs a name")?),
+                "--perceptual" => config.perceptual = true,
+                "--logind" => config.backend = Backend::Logind,
+                "--keyboard" => config.keyboard = true,
+                "--reset-curve" => config.reset_curve = true,
+                "--curve" => config.curve = args.next().context("--curve needs a path")?.into(),
+                "--record" => {
+                    config.record = Some(args.next().context("--record needs a path")?.into())
+                }
+                "--replay" => {
+                    config.replay = Some(args.next().context("--replay needs a trace")?.into())
+                }
+                _ => bail!("unknown argument: {}", arg),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    // write to sysfs ourselves, needs the udev rule
+    Sysfs,
+    // ask logind to write it for our session, needs nothing
+    Logind,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Sampling {
+    Poll,
+    // iio threshold events, falls back to polling without them
+    Events,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Smoothing {
+    Mean,
+    Median,
+    // exponential moving average
+    Ema,
+}
+
+// holds on to the last lux we acted on until the light has changed enough, for
+// long enough, so flickers and passing shadows don't move the backlight
+struct Hysteresis {
+    lux: f32,
+    // brightening or not, and since when
+    pending: Option<(bool, Instant)>,
+    brighten_threshold: f32,
+    brighten_delay: Duration,
+    dim_threshold: f32,
+    dim_delay: Duration,
+}
+
+impl Hysteresis {
+    fn update(&mut self, lux: f32) -> f32 {
+        self.update_at(lux, Instant::now())
+    }
+
+    // at a given time, so replays can go faster than it happened
+    fn update_at(&mut self, lux: f32, now: Instant) -> f32 {
+        let brighter = lux > self.lux * (1. + self.brighten_threshold);
+        let dimmer = lux < self.lux * (1. - self.dim_threshold);
+        if !brighter && !dimmer {
+            self.pending = None;
+            return self.lux;
+        }
+
+        let since = match self.pending {
+            Some((b, since)) if b == brighter => since,
+            _ => {
+                self.pending = Some((brighter, now));
+                now
+            }
+        };
+        let delay = if brighter {
+            self.brighten_delay
+        } else {
+            self.dim_delay
+        };
+        if now.duration_since(since) >= delay {
+            self.lux = lux;
+            self.pending = None;
+        }
+        self.lux
+    }
+
+    fn configure(&mut self, config: &Config) {
+        self.brighten_threshold = config.brighten_threshold;
+        self.brighten_delay = Duration::from_millis(config.brighten_delay_ms);
+        self.dim_threshold = config.dim_threshold;
+        self.dim_delay = Duration::from_millis(config.dim_delay_ms);
+    }
+
+    fn new(config: &Config, lux: f32) -> Self {
+        Self {
+            lux,
+            pending: None,
+            brighten_threshold: config.brighten_threshold,
+            brighten_delay: Duration::from_millis(config.brighten_delay_ms),
+            dim_threshold: config.dim_threshold,
+            dim_delay: Duration::from_millis(config.dim_delay_ms),
+        }
+    }
 }
 
 struct Sensor {
-    samples: VecDeque<u32>,
+    path: PathBuf,
+    channel: Illuminance,
+    max: f32,
+    smoothing: Smoothing,
+    window: usize,
+    ema_alpha: f32,
+    ema: f32,
+    samples: VecDeque<f32>,
+    // the last reading, to tell if the light's holding steady
+    last: f32,
+    events: Option<Events>,
+}
+
+// threshold events from /dev/iio:deviceN
+struct Events {
+    // the events dir of the sensor, where thresholds are set
+    dir: PathBuf,
+    file: AsyncFd<std::fs::File>,
+}
+
+enum Illuminance {
+    // already in lux
+    Input,
+    // lux = (raw + offset) * scale
+    Raw { scale: f32, offset: f32 },
 }
 
 impl Sensor {
-    async fn sample(&mut self) -> Result<()> {
-        self.samples.pop_front();
-        self.samples.push_back(Self::get().await?);
+    fn configure(&mut self, config: &Config) {
+        // pad a bigger window with where we're at, so it doesn't lurch
+        let smoothed = self.smoothed();
+        self.smoothing = config.smoothing;
+        self.window = config.window.max(1);
+        self.ema_alpha = config.ema_alpha.clamp(0., 1.);
+        while self.samples.len() < self.window {
+            self.samples.push_front(smoothed);
+        }
+        while self.samples.len() > self.window {
+            self.samples.pop_front();
+        }
+    }
+
+    // returns if the light held steady since the last one
+    async fn sample(&mut self) -> Result<bool> {
+        let lux = self.get().await?;
+        self.samples.push_back(lux);
+        while self.samples.len() > self.window {
+            self.samples.pop_front();
+        }
+        self.ema += self.ema_alpha * (lux - self.ema);
+        let steady = (lux - self.last).abs() <= (self.last * STEADY).max(1.);
+        self.last = lux;
+        Ok(steady)
+    }
+
+    // sets thresholds around the last reading and sleeps until one's
+    // crossed, or for timeout in case the driver never says
+    async fn wait(&mut self, timeout: Duration) -> Result<()> {
+        let Some(events) = &mut self.events else {
+            nap(timeout).await;
+            return Ok(());
+        };
+        let margin = (self.last * STEADY).max(1.);
+        for (direction, lux) in [
+            ("rising", self.last + margin),
+            ("falling", self.last - margin),
+        ] {
+            // thresholds are in whatever units the channel reads in
+            let value = match self.channel {
+                Illuminance::Input => lux,
+                Illuminance::Raw { scale, offset } => lux / scale - offset,
+            };
+            let threshold = format!("in_illuminance_thresh_{}", direction);
+            write(
+                events.dir.join(format!("{}_value", threshold)),
+                (value.max(0.).round() as u32).to_string(),
+            )
+            .await?;
+            write(events.dir.join(format!("{}_en", threshold)), "1").await?;
+        }
+        let mut event = [0; IIO_EVENT_SIZE];
+        let _ = tokio::time::timeout(timeout, read_ready(&events.file, &mut event)).await;
+        STATS.wakeups.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
-    async fn get() -> Result<u32> {
-        Ok(
-            read_to_string("/sys/bus/iio/devices/iio:device0/in_illuminance_raw")
-                .await?
-                .trim()
-                .parse()?,
-        )
+    fn smoothed(&self) -> f32 {
+        match self.smoothing {
+            Smoothing::Mean => self.samples.iter().sum::<f32>() / self.samples.len() as f32,
+            Smoothing::Median => {
+                let mut sorted: Vec<f32> = self.samples.iter().copied().collect();
+                sorted.sort_by(f32::total_cmp);
+                sorted[sorted.len() / 2]
+            }
+            Smoothing::Ema => self.ema,
+        }
+    }
+
+    async fn get(&self) -> Result<f32> {
+        STATS.sensor_reads.fetch_add(1, Ordering::Relaxed);
+        match self.channel {
+            Illuminance::Input => read_value(self.path.join("in_illuminance_input")).await,
+            Illuminance::Raw { scale, offset } => {
+                let raw: f32 = read_value(self.path.join("in_illuminance_raw")).await?;
+                Ok((raw + offset) * scale)
+            }
+        }
+    }
+
+    async fn find(config: &Config) -> Result<PathBuf> {
+        let bus = config.sysfs_root.join("bus/iio/devices");
+        let mut found = Vec::new();
+        let mut entries = read_dir(&bus)
+            .await
+            .with_context(|| format!("couldn't list {}", bus.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let name = read_to_string(path.join("name")).await.unwrap_or_default();
+            let wanted = match &config.sensor {
+                Some(sensor) => name.trim() == sensor,
+                None => {
+                    path.join("in_illuminance_input").exists()
+                        || path.join("in_illuminance_raw").exists()
+                }
+            };
+            if wanted {
+                found.push(path);
+            }
+        }
+        found.sort();
+        found
+            .into_iter()
+            .next()
+            .ok_or_else(|| match &config.sensor {
+                Some(sensor) => anyhow!("no sensor named {} in {}", sensor, bus.display()),
+                None => anyhow!("no light sensor found in {}", bus.display()),
+            })
+    }
+
+    async fn events(path: &Path) -> Result<Events> {
+        let dir = path.join("events");
+        for direction in ["rising", "falling"] {
+            let en = dir.join(format!("in_illuminance_thresh_{}_en", direction));
+            if !en.exists() {
+                bail!("{} has no {} threshold", path.display(), direction);
+            }
+        }
+        let dev = Path::new("/dev").join(path.file_name().unwrap());
+        let dev = std::fs::File::open(&dev)
+            .with_context(|| format!("couldn't open {}", dev.display()))?;
+        let mut fd: libc::c_int = -1;
+        if unsafe { libc::ioctl(dev.as_raw_fd(), IIO_GET_EVENT_FD_IOCTL as _, &mut fd) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("couldn't get the sensor's event fd");
+        }
+        let file = unsafe { std::fs::File::from_raw_fd(fd) };
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("couldn't make the sensor's event fd nonblocking");
+        }
+        Ok(Events {
+            dir,
+            file: AsyncFd::new(file)?,
+        })
+    }
+
+    // drivers can list what a channel reads as "[min step max]" or "a b c"
+    async fn available(path: PathBuf) -> Option<f32> {
+        read_to_string(path)
+            .await
+            .ok()?
+            .trim_matches(|c: char| c == '[' || c == ']' || c.is_whitespace())
+            .split_whitespace()
+            .filter_map(|v| v.parse().ok())
+            .reduce(f32::max)
     }
-    async fn new() -> Result<Self> {
-        let samples = VecDeque::from([Self::get().await?; SAMPLE_SIZE as usize]);
-        Ok(Self { samples })
+
+    async fn new(config: &Config) -> Result<Self> {
+        let path = Self::find(config).await?;
+        let channel = if path.join("in_illuminance_input").exists() {
+            Illuminance::Input
+        } else {
+            // both are optional, missing means no-op
+            let scale = read_value(path.join("in_illuminance_scale"))
+                .await
+                .unwrap_or(1.);
+            let offset = read_value(path.join("in_illuminance_offset"))
+                .await
+                .unwrap_or(0.);
+            Illuminance::Raw { scale, offset }
+        };
+        let available = match channel {
+            Illuminance::Input => {
+                Self::available(path.join("in_illuminance_input_available")).await
+            }
+            Illuminance::Raw { scale, offset } => {
+                Self::available(path.join("in_illuminance_raw_available"))
+                    .await
+                    .map(|max| (max + offset) * scale)
+            }
+        };
+        let max = config.max_lux.or(available).unwrap_or(DEFAULT_MAX_LUX);
+        let mut sensor = Self {
+            path,
+            channel,
+            max,
+            smoothing: config.smoothing,
+            window: config.window.max(1),
+            ema_alpha: config.ema_alpha.clamp(0., 1.),
+            ema: 0.,
+            samples: VecDeque::new(),
+            last: 0.,
+            events: None,
+        };
+        if let Sampling::Events = config.sampling {
+            match Self::events(&sensor.path).await {
+                Ok(events) => sensor.events = Some(events),
+                Err(e) => eprintln!("{:#}, polling instead", e),
+            }
+        }
+        let lux = sensor.get().await?;
+        sensor.samples = VecDeque::from(vec![lux; sensor.window]);
+        sensor.ema = lux;
+        sensor.last = lux;
+        Ok(sensor)
     }
 }
 
 struct Backlight {
+    path: PathBuf,
+    // only with Backend::Logind
+    logind: Option<zbus::Connection>,
     requested: u32,
     target: u32,
     diff: i32,
-    step: i32,
+    // in the mapping's brightness units, not raw
+    step: f32,
+    // keys are in the mapping's units too
     curve: Spline<f32, f32>,
+    mapping: Mapping,
+    ramp_frames: u32,
+    settle: Duration,
+    // nobody's used the laptop for a while
+    dimmed: bool,
+    dim_factor: f32,
+    schedule: Option<Schedule>,
+    battery: Option<Battery>,
+    // brightest the sensor reads, for a fresh curve
+    max_lux: f32,
+    curve_path: PathBuf,
+    // from `ctl`, held until resumed, or until a time after a manual change
+    held: bool,
+    paused_until: Option<Instant>,
+    // from `ctl`, as a fraction of the max, until changed by hand
+    offset: f32,
 }
 
 impl Backlight {
-    async fn prepare(&mut self, s: u32) -> Result<()> {
-        self.target = self.curve.clamped_sample(s as f32).unwrap() as u32;
-        self.diff = self.target as i32 - Self::get().await? as i32;
-        self.step = self.diff / FPS as i32;
+    async fn prepare(&mut self, s: f32) -> Result<()> {
+        let s = self.mapping.encode_lux(s);
+        if self.curve.stretch(s) {
+            save_curve(&self.curve_path, &self.curve, self.mapping).await?;
+        }
+        let mut raw = self
+            .mapping
+            .decode_brightness(self.curve.clamped_sample(s).unwrap());
+        if self.dimmed {
+            raw *= self.dim_factor;
+        }
+        if let Some(schedule) = &self.schedule {
+            let night = schedule.night(Local::now());
+            raw *= schedule.factor(night);
+            if let Some(cap) = schedule.cap(night) {
+                raw = raw.min(cap * self.mapping.max);
+            }
+        }
+        if let Some(battery) = &mut self.battery {
+            if battery.low().await {
+                raw *= battery.factor;
+                if let Some(cap) = battery.cap {
+                    raw = raw.min(cap * self.mapping.max);
+                }
+            }
+        }
+        if self.offset != 0. {
+            raw = (raw + self.offset * self.mapping.max).clamp(1., self.mapping.max);
+        }
+        let raw = raw.round() as u32;
+        let current = self.get().await?;
+        self.diff = raw as i32 - current as i32;
+        // only when the target moves, so the ramp is even from where it started
+        if raw != self.target || (self.diff > 0) != (self.step > 0.) {
+            self.target = raw;
+            self.step = (self.mapping.encode_brightness(raw as f32)
+                - self.mapping.encode_brightness(current as f32))
+                / self.ramp_frames as f32;
+        }
         Ok(())
     }
 
     async fn adjust(&mut self) -> Result<()> {
-        self.diff = self.target as i32 - Self::get().await? as i32;
-        if self.step == 0 {
-            self.step = if self.diff > 0 { 1 } else { -1 }
+        let current = self.get().await?;
+        self.diff = self.target as i32 - current as i32;
+        let mut v = self
+            .mapping
+            .decode_brightness(self.mapping.encode_brightness(current as f32) + self.step)
+            .round() as i32;
+        // small steps can round to nothing, and big ones can overshoot
+        if v == current as i32 {
+            v += self.diff.signum();
+        }
+        if (self.diff > 0 && v > self.target as i32) || (self.diff < 0 && v < self.target as i32) {
+            v = self.target as i32;
         }
-        let v = Self::get().await? as i32 + self.step;
         if v < 0 {
             return Ok(());
         }
@@ -117,90 +1350,1542 @@ impl Backlight {
         Ok(())
     }
 
-    async fn retain(&mut self, s: u32) -> Result<()> {
-        sleep(Duration::from_secs(5)).await;
-        let current = Self::get().await?;
-
+    // learns where it was left once whoever was changing it is done, see Settling
+    async fn retain(&mut self, s: f32, current: u32) -> Result<()> {
         if current == 0 {
             return Ok(());
         }
 
         self.requested = current;
-        self.curve.monotonic_add(s as f32, current as f32);
+        // they've picked where they want it now
+        self.offset = 0.;
+        // learn what it'd be during the day, prepare() scales it back down
+        let mut learned = current as f32;
+        if let Some(schedule) = &self.schedule {
+            learned /= schedule.factor(schedule.night(Local::now()));
+        }
+        if let Some(battery) = self.battery.as_ref().filter(|battery| battery.low) {
+            learned /= battery.factor;
+        }
+        let k = self.mapping.encode_lux(s);
+        self.curve.stretch(k);
+        self.curve
+            .monotonic_add(k, self.mapping.encode_brightness(learned));
+        save_curve(&self.curve_path, &self.curve, self.mapping).await?;
         self.prepare(s).await?;
         Ok(())
     }
 
-    async fn changed(&self) -> Result<bool> {
-        Ok(Self::get().await? as i32 - self.requested as i32 != 0)
+    fn paused(&self) -> bool {
+        self.held
+            || self
+                .paused_until
+                .is_some_and(|until| Instant::now() < until)
     }
 
-    async fn get() -> Result<u32> {
-        Ok(
-            read_to_string("/sys/class/backlight/intel_backlight/brightness")
-                .await?
-                .trim()
-                .parse()?,
-        )
+    async fn command(&mut self, command: Command, lux: f32) -> String {
+        let done = match command {
+            Command::Pause(None) => {
+                self.held = true;
+                Ok(())
+            }
+            Command::Pause(Some(minutes)) => {
+                self.paused_until = Some(Instant::now() + Duration::from_secs(minutes * 60));
+                Ok(())
+            }
+            Command::Resume => {
+                self.held = false;
+                self.paused_until = None;
+                Ok(())
+            }
+            Command::Offset(offset) => {
+                self.offset = offset;
+                Ok(())
+            }
+            Command::Stats => {
+                return toml_edit::easy::to_string(&STATS.report()).unwrap_or_default();
+            }
+            Command::Status => {
+                return match self.status(lux).await {
+                    Ok(status) => toml_edit::easy::to_string(&status).unwrap_or_default(),
+                    Err(e) => format!("error: {:#}\n", e),
+                };
+            }
+            Command::ResetCurve => match reset_curve(&self.curve_path).await {
+                Ok(()) => self.reload_curve().await,
+                Err(e) => Err(e),
+            },
+            Command::ReloadCurve => self.reload_curve().await,
+        };
+        match done {
+            Ok(()) => String::from("ok\n"),
+            Err(e) => format!("error: {:#}\n", e),
+        }
+    }
+
+    async fn configure(&mut self, config: &Config) -> Result<()> {
+        let mapping = Mapping {
+            perceptual: config.perceptual,
+            gamma: config.gamma,
+            max: self.mapping.max,
+        };
+        // the curve's kept in the mapping's terms
+        if mapping != self.mapping {
+            self.mapping = mapping;
+            self.reload_curve().await?;
+        }
+        self.ramp_frames = (config.ramp_ms / TPF).max(1) as u32;
+        self.settle = Duration::from_millis(config.settle_ms);
+        self.dim_factor = config.idle_dim_factor.clamp(0., 1.);
+        self.schedule = Schedule::new(config)?;
+        self.battery = config.battery.then(|| Battery::new(config));
+        Ok(())
+    }
+
+    async fn reload_curve(&mut self) -> Result<()> {
+        self.curve = initial_curve(&self.curve_path, self.mapping, self.max_lux).await?;
+        Ok(())
+    }
+
+    async fn status(&self, lux: f32) -> Result<Status> {
+        Ok(Status {
+            lux,
+            target: self.target,
+            brightness: self.get().await?,
+            max_brightness: self.mapping.max as u32,
+            paused: self.paused(),
+            // without the noise from widening it
+            offset: (self.offset as f64 * 1000.).round() / 1000.,
+        })
+    }
+
+    async fn get(&self) -> Result<u32> {
+        STATS.backlight_reads.fetch_add(1, Ordering::Relaxed);
+        read_value(self.path.join("brightness")).await
     }
 
     async fn set(&mut self, val: u32) -> Result<()> {
-        write(
-            "/sys/class/backlight/intel_backlight/brightness",
-            val.to_string(),
-        )
-        .await?;
-        self.requested = val;
+        STATS.backlight_writes.fetch_add(1, Ordering::Relaxed);
+        set_brightness(self.logind.as_ref(), "backlight", &self.path, val).await?;
+        // read it back, some drivers round what they're given
+        self.requested = self.get().await?;
         Ok(())
     }
 
-    async fn max() -> Result<u32> {
-        Ok(
-            read_to_string("/sys/class/backlight/intel_backlight/max_brightness")
-                .await?
-                .trim()
-                .parse()?,
-        )
+    async fn find(config: &Config) -> Result<PathBuf> {
+        let class = config.sysfs_root.join("class/backlight");
+        if let Some(name) = &config.device {
+            let path = class.join(name);
+            if !path.join("brightness").exists() {
+                bail!("no backlight named {} in {}", name, class.display());
+            }
+            return Ok(path);
+        }
+
+        let mut found = Vec::new();
+        let mut entries = read_dir(&class)
+            .await
+            .with_context(|| format!("couldn't list {}", class.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let kind = read_to_string(entry.path().join("type"))
+                .await
+                .unwrap_or_default();
+            if let Some(rank) = BACKLIGHT_TYPES.iter().position(|&t| t == kind.trim()) {
+                found.push((rank, entry.path()));
+            }
+        }
+        // sort by path too so e.g. amdgpu_bl0 wins over amdgpu_bl1 every time
+        found.sort();
+        found
+            .into_iter()
+            .next()
+            .map(|(_, path)| path)
+            .ok_or_else(|| anyhow!("no backlight found in {}", class.display()))
     }
-    async fn new() -> Result<Self> {
-        let current = Self::get().await?;
+
+    async fn new(config: &Config, max_lux: f32, logind: Option<zbus::Connection>) -> Result<Self> {
+        let path = Self::find(config).await?;
+        let current = read_value(path.join("brightness")).await?;
+        let max: u32 = read_value(path.join("max_brightness")).await?;
         let requested = current;
         let target = current;
         let diff = 0;
-        let step = 0;
-        let floor = Key::new(0., 1., Interpolation::default());
-        let ceil = Key::new(3355., Self::max().await? as f32, Interpolation::default());
-        let curve = Spline::from_vec(vec![floor, ceil]);
+        let step = 0.;
+        let mapping = Mapping {
+            perceptual: config.perceptual,
+            gamma: config.gamma,
+            max: max as f32,
+        };
+        let curve = initial_curve(&config.curve, mapping, max_lux).await?;
         Ok(Self {
+            path,
+            logind,
             requested,
             target,
             diff,
             step,
             curve,
+            mapping,
+            ramp_frames: (config.ramp_ms / TPF).max(1) as u32,
+            settle: Duration::from_millis(config.settle_ms),
+            dimmed: false,
+            dim_factor: config.idle_dim_factor.clamp(0., 1.),
+            schedule: Schedule::new(config)?,
+            battery: config.battery.then(|| Battery::new(config)),
+            max_lux,
+            curve_path: config.curve.clone(),
+            held: false,
+            paused_until: None,
+            offset: 0.,
         })
     }
 }
 
-trait Monotonic<T, U> {
-    fn monotonic_add(&mut self, k: T, v: U);
+// where the curve is interpolated and ramped. linear works on raw lux and
+// brightness, perceptual on log lux and gamma encoded brightness, so the same
+// change in either looks about as big wherever it happens
+#[derive(Clone, Copy, PartialEq)]
+struct Mapping {
+    perceptual: bool,
+    gamma: f32,
+    max: f32,
 }
 
-impl Monotonic<f32, f32> for Spline<f32, f32> {
-    fn monotonic_add(&mut self, k: f32, v: f32) {
-        // check if key exists and update or add new key
-        if let Some(key) = self.keys().iter().position(|&key| key.t == k) {
-            *self.get_mut(key).unwrap().value = v;
+impl Mapping {
+    fn encode_lux(&self, lux: f32) -> f32 {
+        if self.perceptual {
+            lux.max(0.).ln_1p()
         } else {
-            let k = Key::new(k, v, Interpolation::default());
-            self.add(k);
+            lux
         }
+    }
 
-        // make keys with values in the wrong direction consistent
-        if let Some(idx) = self.keys().iter().position(|&key| {
-            (key.t != 0. && key.t != 3355.)
-                && ((key.value > v && key.t < k) || (key.value < v && key.t > k))
+    fn decode_lux(&self, t: f32) -> f32 {
+        if self.perceptual {
+            t.exp_m1()
+        } else {
+            t
+        }
+    }
+
+    fn encode_brightness(&self, raw: f32) -> f32 {
+        if self.perceptual {
+            (raw / self.max).clamp(0., 1.).powf(self.gamma.recip())
+        } else {
+            raw
+        }
+    }
+
+    fn decode_brightness(&self, v: f32) -> f32 {
+        if self.perceptual {
+            v.clamp(0., 1.).powf(self.gamma) * self.max
+        } else {
+            v
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+enum TimeOfDay {
+    // minutes after midnight
+    At(f64),
+    Sunrise,
+    Sunset,
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        match s.as_str() {
+            "sunrise" => Ok(TimeOfDay::Sunrise),
+            "sunset" => Ok(TimeOfDay::Sunset),
+            _ => {
+                let (h, m) = s
+                    .split_once(':')
+                    .with_context(|| format!("{} isn't HH:MM, sunrise or sunset", s))?;
+                let (h, m): (u32, u32) = (h.parse()?, m.parse()?);
+                if h > 23 || m > 59 {
+                    bail!("{} isn't a time of day", s);
+                }
+                Ok(TimeOfDay::At((h * 60 + m) as f64))
+            }
+        }
+    }
+}
+
+// makes the screen dimmer at night than the curve alone would
+struct Schedule {
+    start: TimeOfDay,
+    end: TimeOfDay,
+    fade: f64,
+    factor: f32,
+    cap: Option<f32>,
+    latitude: f64,
+    longitude: f64,
+}
+
+impl Schedule {
+    // how far into the night we are, 0 during the day to 1 once faded in
+    fn night(&self, now: chrono::DateTime<Local>) -> f32 {
+        let minute = (now.hour() * 60 + now.minute()) as f64 + now.second() as f64 / 60.;
+        let offset = now.offset().local_minus_utc() as f64 / 60.;
+        let (sunrise, sunset) = sun_times(now.ordinal(), self.latitude, self.longitude, offset);
+        let at = |time| match time {
+            TimeOfDay::At(minute) => minute,
+            TimeOfDay::Sunrise => sunrise,
+            TimeOfDay::Sunset => sunset,
+        };
+        let (start, end) = (at(self.start), at(self.end));
+
+        // the night can wrap past midnight
+        let length = (end - start).rem_euclid(1440.);
+        let since_start = (minute - start).rem_euclid(1440.);
+        if since_start >= length {
+            return 0.;
+        }
+        if self.fade <= 0. {
+            return 1.;
+        }
+        let until_end = length - since_start;
+        (since_start.min(until_end) / self.fade).min(1.) as f32
+    }
+
+    fn factor(&self, night: f32) -> f32 {
+        1. + (self.factor - 1.) * night
+    }
+
+    // as a fraction of the max brightness
+    fn cap(&self, night: f32) -> Option<f32> {
+        self.cap.map(|cap| 1. + (cap - 1.) * night)
+    }
+
+    fn new(config: &Config) -> Result<Option<Self>> {
+        if !config.night {
+            return Ok(None);
+        }
+        let by_sun = |time| matches!(time, TimeOfDay::Sunrise | TimeOfDay::Sunset);
+        let (latitude, longitude) = match (config.latitude, config.longitude) {
+            (Some(latitude), Some(longitude)) => (latitude, longitude),
+            _ if by_sun(config.night_start) || by_sun(config.night_end) => {
+                bail!("sunrise and sunset need latitude and longitude set")
+            }
+            _ => (0., 0.),
+        };
+        Ok(Some(Self {
+            start: config.night_start,
+            end: config.night_end,
+            fade: config.night_fade_minutes,
+            factor: config.night_factor.clamp(0.01, 1.),
+            cap: config.night_cap.map(|cap| cap.clamp(0., 1.)),
+            latitude,
+            longitude,
+        }))
+    }
+}
+
+// sunrise and sunset in minutes after local midnight, from NOAA's general
+// solar position calculations. good to a few minutes, which is plenty here
+fn sun_times(day: u32, latitude: f64, longitude: f64, utc_offset: f64) -> (f64, f64) {
+    use std::f64::consts::PI;
+
+    let g = 2. * PI / 365. * (day as f64 - 1.);
+    let eqtime = 229.18
+        * (0.000075 + 0.001868 * g.cos()
+            - 0.032077 * g.sin()
+            - 0.014615 * (2. * g).cos()
+            - 0.040849 * (2. * g).sin());
+    let decl = 0.006918 - 0.399912 * g.cos() + 0.070257 * g.sin() - 0.006758 * (2. * g).cos()
+        + 0.000907 * (2. * g).sin()
+        - 0.002697 * (3. * g).cos()
+        + 0.00148 * (3. * g).sin();
+    let latitude = latitude.to_radians();
+    // clamping makes polar night a day of no length, and polar day a whole one
+    let cos_ha = (90.833f64.to_radians().cos() / (latitude.cos() * decl.cos())
+        - latitude.tan() * decl.tan())
+    .clamp(-1., 1.);
+    let ha = cos_ha.acos().to_degrees();
+    let noon = 720. - 4. * longitude - eqtime + utc_offset;
+    (noon - 4. * ha, noon + 4. * ha)
+}
+
+// saves some power once the battery's running low
+struct Battery {
+    class: PathBuf,
+    threshold: u32,
+    factor: f32,
+    cap: Option<f32>,
+    low: bool,
+    checked: Option<Instant>,
+}
+
+impl Battery {
+    // only looks every so often, this gets called every frame
+    async fn low(&mut self) -> bool {
+        if self
+            .checked
+            .is_some_and(|checked| checked.elapsed() < Duration::from_secs(10))
+        {
+            return self.low;
+        }
+        self.checked = Some(Instant::now());
+        self.low = self.check().await.unwrap_or(false);
+        self.low
+    }
+
+    async fn check(&self) -> Result<bool> {
+        let mut plugged = false;
+        let mut capacity = None;
+        let mut entries = read_dir(&self.class).await?;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            // mice, headsets and such, which don't power us
+            let scope: String = read_value(path.join("scope")).await.unwrap_or_default();
+            if scope == "Device" {
+                continue;
+            }
+            let kind: String = read_value(path.join("type")).await.unwrap_or_default();
+            // one that can't be read says nothing about the others
+            match kind.as_str() {
+                "Mains" => {
+                    if let Ok(online) = read_value::<u32>(path.join("online")).await {
+                        plugged |= online == 1;
+                    }
+                }
+                "Battery" => {
+                    if let Ok(c) = read_value::<u32>(path.join("capacity")).await {
+                        capacity = Some(capacity.map_or(c, |capacity: u32| capacity.min(c)));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(!plugged && capacity.is_some_and(|capacity| capacity <= self.threshold))
+    }
+
+    fn new(config: &Config) -> Self {
+        Self {
+            class: config.sysfs_root.join("class/power_supply"),
+            threshold: config.battery_threshold,
+            factor: config.battery_factor.clamp(0.01, 1.),
+            cap: config.battery_cap.map(|cap| cap.clamp(0., 1.)),
+            low: false,
+            checked: None,
+        }
+    }
+}
+
+// lid shut or panel blanked, there's nothing worth adjusting then
+struct Screen {
+    sysfs_root: PathBuf,
+    proc_root: PathBuf,
+    backlight: PathBuf,
+    // only with Backend::Logind
+    logind: Option<zbus::Connection>,
+}
+
+impl Screen {
+    async fn off(&self) -> bool {
+        self.blanked().await || self.lid_closed().await
+    }
+
+    async fn blanked(&self) -> bool {
+        // 0 is on, anything else is some kind of off
+        let bl_power = read_value::<u32>(self.backlight.join("bl_power")).await;
+        if bl_power.is_ok_and(|power| power != 0) {
+            return true;
+        }
+
+        let drm = self.sysfs_root.join("class/drm");
+        let Ok(mut entries) = read_dir(&drm).await else {
+            return false;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            // only the internal panel
+            if !entry.file_name().to_string_lossy().contains("eDP") {
+                continue;
+            }
+            let dpms = read_to_string(entry.path().join("dpms")).await;
+            if dpms.is_ok_and(|dpms| dpms.trim() == "Off") {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn lid_closed(&self) -> bool {
+        let lid = self.proc_root.join("acpi/button/lid");
+        if let Ok(mut entries) = read_dir(&lid).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let state = read_to_string(entry.path().join("state")).await;
+                if state.is_ok_and(|state| state.contains("closed")) {
+                    return true;
+                }
+            }
+            return false;
+        }
+
+        // no acpi lid, ask logind if we're talking to it anyway
+        let Some(logind) = &self.logind else {
+            return false;
+        };
+        let reply = logind
+            .call_method(
+                Some("org.freedesktop.login1"),
+                "/org/freedesktop/login1",
+                Some("org.freedesktop.DBus.Properties"),
+                "Get",
+                &("org.freedesktop.login1.Manager", "LidClosed"),
+            )
+            .await;
+        reply
+            .ok()
+            .and_then(|reply| reply.body::<zbus::zvariant::OwnedValue>().ok())
+            .and_then(|closed| bool::try_from(closed).ok())
+            .unwrap_or(false)
+    }
+}
+
+// on by the light alone, off when it's bright or nobody's typing
+struct Keyboard {
+    path: PathBuf,
+    // only with Backend::Logind
+    logind: Option<zbus::Connection>,
+    level: u32,
+    on_lux: f32,
+    off_lux: f32,
+    idle: Duration,
+    dark: bool,
+    // what we last set it to, so it's left alone otherwise
+    lit: Option<bool>,
+}
+
+impl Keyboard {
+    async fn update(&mut self, lux: f32, idle: Duration) -> Result<()> {
+        if lux < self.on_lux {
+            self.dark = true;
+        } else if lux > self.off_lux {
+            self.dark = false;
+        }
+        let lit = self.dark && (self.idle.is_zero() || idle < self.idle);
+        if self.lit != Some(lit) {
+            let val = if lit { self.level } else { 0 };
+            set_brightness(self.logind.as_ref(), "leds", &self.path, val).await?;
+            self.lit = Some(lit);
+        }
+        Ok(())
+    }
+
+    async fn find(config: &Config) -> Result<PathBuf> {
+        let class = config.sysfs_root.join("class/leds");
+        if let Some(name) = &config.keyboard_device {
+            let path = class.join(name);
+            if !path.join("brightness").exists() {
+                bail!(
+                    "no keyboard backlight named {} in {}",
+                    name,
+                    class.display()
+                );
+            }
+            return Ok(path);
+        }
+
+        let mut found = Vec::new();
+        let mut entries = read_dir(&class)
+            .await
+            .with_context(|| format!("couldn't list {}", class.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with("kbd_backlight")
+            {
+                found.push(entry.path());
+            }
+        }
+        found.sort();
+        found
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no keyboard backlight found in {}", class.display()))
+    }
+
+    async fn configure(&mut self, config: &Config) -> Result<()> {
+        self.level = match config.keyboard_level {
+            Some(level) => level,
+            None => read_value(self.path.join("max_brightness")).await?,
+        };
+        self.on_lux = config.keyboard_on_lux;
+        self.off_lux = config.keyboard_off_lux;
+        self.idle = Duration::from_secs(config.keyboard_idle_secs);
+        // so a new level's set
+        self.lit = None;
+        Ok(())
+    }
+
+    async fn new(config: &Config, logind: Option<zbus::Connection>) -> Result<Self> {
+        let mut keyboard = Self {
+            path: Self::find(config).await?,
+            logind,
+            level: 0,
+            on_lux: 0.,
+            off_lux: 0.,
+            idle: Duration::ZERO,
+            dark: false,
+            lit: None,
+        };
+        keyboard.configure(config).await?;
+        Ok(keyboard)
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+struct MonitorConfig {
+    // like "i2c-5", see /dev
+    bus: String,
+    // from the panel's brightness, as a fraction, to the monitor's, as a
+    // percent of its max. the same percent if not given
+    curve: Option<Vec<(f32, f32)>>,
+}
+
+// what DDC/CI needs from an i2c bus, so a fake monitor can stand in
+trait I2c {
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+    fn read(&mut self, data: &mut [u8]) -> Result<()>;
+}
+
+// /dev/i2c-N, talking to the monitor's DDC address
+struct I2cDev(std::fs::File);
+
+impl I2cDev {
+    fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("couldn't open {}", path.display()))?;
+        if unsafe { libc::ioctl(file.as_raw_fd(), I2C_SLAVE as _, DDC_ADDR as libc::c_ulong) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("couldn't address {}", path.display()));
+        }
+        Ok(Self(file))
+    }
+}
+
+impl I2c for I2cDev {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        Ok(self.0.write_all(data)?)
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<()> {
+        Ok(self.0.read_exact(data)?)
+    }
+}
+
+// answers VCP brightness gets and sets like a monitor would
+struct FakeMonitor {
+    brightness: u16,
+    max: u16,
+    reply: Vec<u8>,
+}
+
+impl FakeMonitor {
+    fn new(max: u16) -> Self {
+        Self {
+            brightness: max,
+            max,
+            reply: Vec::new(),
+        }
+    }
+}
+
+impl I2c for FakeMonitor {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let [0x51, len, ref payload @ .., checksum] = data[..] else {
+            bail!("not a DDC/CI message");
+        };
+        if payload.len() != (len & 0x7f) as usize
+            || ddc_checksum(DDC_ADDR << 1, &data[..data.len() - 1]) != checksum
+        {
+            bail!("bad DDC/CI message");
+        }
+        match payload {
+            [0x01, code] => {
+                let supported = (*code == VCP_BRIGHTNESS) as u8 ^ 1;
+                let [max_hi, max_lo] = self.max.to_be_bytes();
+                let [hi, lo] = self.brightness.to_be_bytes();
+                let mut reply = vec![
+                    0x6e, 0x88, 0x02, supported, *code, 0, max_hi, max_lo, hi, lo,
+                ];
+                reply.push(ddc_checksum(0x50, &reply));
+                self.reply = reply;
+            }
+            [0x03, VCP_BRIGHTNESS, hi, lo] => {
+                self.brightness = u16::from_be_bytes([*hi, *lo]).min(self.max);
+            }
+            _ => bail!("unsupported DDC/CI request"),
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<()> {
+        let reply = std::mem::take(&mut self.reply);
+        if reply.len() != data.len() {
+            bail!("nothing to read");
+        }
+        data.copy_from_slice(&reply);
+        Ok(())
+    }
+}
+
+fn ddc_checksum(start: u8, data: &[u8]) -> u8 {
+    data.iter().fold(start, |checksum, byte| checksum ^ byte)
+}
+
+struct Ddc {
+    bus: Box<dyn I2c + Send>,
+    // monitors want a moment after each request
+    wait: Duration,
+}
+
+impl Ddc {
+    fn send(&mut self, payload: &[u8]) -> Result<()> {
+        let mut message = vec![0x51, 0x80 | payload.len() as u8];
+        message.extend_from_slice(payload);
+        message.push(ddc_checksum(DDC_ADDR << 1, &message));
+        self.bus.write(&message)?;
+        std::thread::sleep(self.wait);
+        Ok(())
+    }
+
+    // returns the brightness and its max
+    fn get(&mut self) -> Result<(u16, u16)> {
+        self.send(&[0x01, VCP_BRIGHTNESS])?;
+        let mut reply = [0; 11];
+        self.bus.read(&mut reply)?;
+        if ddc_checksum(0x50, &reply[..10]) != reply[10] {
+            bail!("bad checksum in DDC/CI reply");
+        }
+        if reply[2] != 0x02 || reply[4] != VCP_BRIGHTNESS {
+            bail!("unexpected DDC/CI reply");
+        }
+        if reply[3] != 0 {
+            bail!("monitor doesn't support setting brightness");
+        }
+        Ok((
+            u16::from_be_bytes([reply[8], reply[9]]),
+            u16::from_be_bytes([reply[6], reply[7]]),
+        ))
+    }
+
+    fn set(&mut self, val: u16) -> Result<()> {
+        let [hi, lo] = val.to_be_bytes();
+        self.send(&[0x03, VCP_BRIGHTNESS, hi, lo])
+    }
+}
+
+struct Monitor {
+    bus: String,
+    ddc: Ddc,
+    max: u16,
+    curve: Spline<f32, f32>,
+    // only write when it changes, it's slow and monitors don't love it
+    set: Option<u16>,
+    // so a monitor that's gone quiet complains once, not every second
+    failing: bool,
+}
+
+impl Monitor {
+    fn update(&mut self, fraction: f32) {
+        let percent = self.curve.clamped_sample(fraction).unwrap().clamp(0., 100.);
+        let val = (percent / 100. * self.max as f32).round() as u16;
+        if self.set == Some(val) {
+            return;
+        }
+        match self.ddc.set(val) {
+            Ok(()) => {
+                self.set = Some(val);
+                self.failing = false;
+            }
+            Err(e) if !self.failing => {
+                eprintln!("couldn't set the brightness on {}: {:#}", self.bus, e);
+                self.failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    fn new(bus: String, mut ddc: Ddc, curve: Option<Vec<(f32, f32)>>) -> Result<Self> {
+        let keys = curve.unwrap_or_else(|| vec![(0., 0.), (1., 100.)]);
+        if keys.len() < 2 {
+            bail!("the curve for {} needs at least two keys", bus);
+        }
+        let curve = Spline::from_iter(
+            keys.into_iter()
+                .map(|(t, v)| Key::new(t, v, Interpolation::default())),
+        );
+        let (_, max) = ddc.get().with_context(|| format!("no DDC/CI on {}", bus))?;
+        Ok(Self {
+            bus,
+            ddc,
+            max,
+            curve,
+            set: None,
+            failing: false,
+        })
+    }
+
+    // connected external outputs, by the i2c bus their ddc is on
+    async fn find(config: &Config) -> Vec<String> {
+        let drm = config.sysfs_root.join("class/drm");
+        let mut found = Vec::new();
+        let Ok(mut entries) = read_dir(&drm).await else {
+            return found;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // card0-DP-1 and the like, but not the panel
+            if !name.contains('-') || name.contains("eDP") || name.contains("LVDS") {
+                continue;
+            }
+            let status = read_to_string(entry.path().join("status")).await;
+            if status.map_or(true, |status| status.trim() != "connected") {
+                continue;
+            }
+            // hdmi and dvi link to theirs, displayport has an aux bus under it
+            if let Ok(ddc) = tokio::fs::read_link(entry.path().join("ddc")).await {
+                found.extend(
+                    ddc.file_name()
+                        .map(|bus| bus.to_string_lossy().into_owned()),
+                );
+                continue;
+            }
+            let Ok(mut children) = read_dir(entry.path()).await else {
+                continue;
+            };
+            while let Ok(Some(child)) = children.next_entry().await {
+                let child = child.file_name().to_string_lossy().into_owned();
+                if child.starts_with("i2c-") {
+                    found.push(child);
+                }
+            }
+        }
+        found.sort();
+        found
+    }
+
+    // those that answer, anything that doesn't is left out
+    async fn all(config: &Config) -> Vec<Self> {
+        let configured = if config.monitors.is_empty() {
+            Self::find(config)
+                .await
+                .into_iter()
+                .map(|bus| MonitorConfig { bus, curve: None })
+                .collect()
+        } else {
+            config.monitors.clone()
+        };
+        let mut monitors = Vec::new();
+        for monitor in configured {
+            let opened = I2cDev::open(&Path::new("/dev").join(&monitor.bus)).and_then(|dev| {
+                let ddc = Ddc {
+                    bus: Box::new(dev),
+                    wait: Duration::from_millis(50),
+                };
+                Self::new(monitor.bus.clone(), ddc, monitor.curve)
+            });
+            match opened {
+                Ok(monitor) => monitors.push(monitor),
+                Err(e) => eprintln!("leaving out {}: {:#}", monitor.bus, e),
+            }
+        }
+        monitors
+    }
+}
+
+// subsystem is "backlight" or "leds", as logind wants it
+async fn set_brightness(
+    logind: Option<&zbus::Connection>,
+    subsystem: &str,
+    path: &Path,
+    val: u32,
+) -> Result<()> {
+    match logind {
+        Some(logind) => {
+            let name = path.file_name().unwrap().to_string_lossy();
+            logind
+                .call_method(
+                    Some("org.freedesktop.login1"),
+                    "/org/freedesktop/login1/session/auto",
+                    Some("org.freedesktop.login1.Session"),
+                    "SetBrightness",
+                    &(subsystem, name.as_ref(), val),
+                )
+                .await
+                .with_context(|| format!("logind couldn't set the brightness of {}", name))?;
+        }
+        None => write(path.join("brightness"), val.to_string())
+            .await
+            .with_context(|| format!("couldn't write {}", path.display()))?,
+    }
+    Ok(())
+}
+
+// what's been going on with the input devices we watch
+struct Input {
+    brightness_keys: AtomicBool,
+    // ms after start
+    last_active: AtomicU64,
+    start: Instant,
+}
+
+impl Input {
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_active.store(now, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+
+    fn new() -> Self {
+        Self {
+            brightness_keys: AtomicBool::new(false),
+            last_active: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+}
+
+// spawns a reader for every input device with brightness keys, or with any
+// keys or buttons at all if everything, which are most of them. returns how
+// many it could open
+async fn watch_input(config: &Config, input: Arc<Input>, everything: bool) -> usize {
+    let class = config.sysfs_root.join("class/input");
+    let Ok(mut entries) = read_dir(&class).await else {
+        return 0;
+    };
+    // devices opened for idle timeouts still have the keys, which shouldn't
+    // pause anything unless asked to
+    let brightness_keys = config.brightness_keys;
+    let mut watching = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("event") {
+            continue;
+        }
+        let caps = entry.path().join("device/capabilities");
+        let keys = read_to_string(caps.join("key")).await.unwrap_or_default();
+        let events = read_to_string(caps.join("ev")).await.unwrap_or_default();
+        let wanted = has_key(&keys, KEY_BRIGHTNESSUP)
+            || has_key(&keys, KEY_BRIGHTNESSDOWN)
+            || (everything && has_key(&events, EV_KEY));
+        if !wanted {
+            continue;
+        }
+        // needs the input group, or root
+        let device = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(Path::new("/dev/input").join(&name))
+            .and_then(AsyncFd::new);
+        let device = match device {
+            Ok(device) => device,
+            Err(e) => {
+                eprintln!("couldn't open /dev/input/{}: {}", name, e);
+                continue;
+            }
+        };
+
+        let input = input.clone();
+        spawn(async move {
+            // evdev hands over whole events, as many as fit
+            let mut events = [0; INPUT_EVENT_SIZE * 16];
+            while let Ok(n @ 1..) = read_ready(&device, &mut events).await {
+                STATS.wakeups.fetch_add(1, Ordering::Relaxed);
+                for event in events[..n].chunks_exact(INPUT_EVENT_SIZE) {
+                    // timeval's size depends on the arch, what follows it doesn't
+                    let tail = &event[INPUT_EVENT_SIZE - 8..];
+                    let kind = u16::from_ne_bytes([tail[0], tail[1]]);
+                    let code = u16::from_ne_bytes([tail[2], tail[3]]);
+                    let value = i32::from_ne_bytes([tail[4], tail[5], tail[6], tail[7]]);
+                    if kind != EV_SYN {
+                        input.touch();
+                    }
+                    // 1 is down, 2 is held
+                    if brightness_keys
+                        && kind == EV_KEY
+                        && (code == KEY_BRIGHTNESSUP || code == KEY_BRIGHTNESSDOWN)
+                        && value != 0
+                    {
+                        input.brightness_keys.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+        watching += 1;
+    }
+    watching
+}
+
+// capabilities are bitmaps in hex words the size of a long, most significant
+// first
+fn has_key(caps: &str, key: u16) -> bool {
+    let bits = usize::BITS as usize;
+    caps.split_whitespace()
+        .rev()
+        .nth(key as usize / bits)
+        .and_then(|word| u64::from_str_radix(word, 16).ok())
+        .is_some_and(|word| word >> (key as usize % bits) & 1 == 1)
+}
+
+// what the curve learned in retain(), so it survives restarts
+#[derive(Deserialize, Serialize)]
+struct SavedCurve {
+    // (lux, brightness)
+    keys: Vec<(f32, f32)>,
+}
+
+fn curve_path() -> PathBuf {
+    let mut path = dirs::state_dir().unwrap();
+    path.push("fwtb");
+    path.push("curve.toml");
+    path
+}
+
+// always saved as raw lux and brightness, whatever the mapping
+async fn load_curve(path: &Path, mapping: Mapping) -> Option<Spline<f32, f32>> {
+    let s = read_to_string(path).await.ok()?;
+    match toml_edit::easy::from_str::<SavedCurve>(&s) {
+        Ok(saved) if saved.keys.len() >= 2 => {
+            Some(Spline::from_iter(saved.keys.into_iter().map(|(t, v)| {
+                Key::new(
+                    mapping.encode_lux(t),
+                    mapping.encode_brightness(v),
+                    Interpolation::default(),
+                )
+            })))
+        }
+        _ => {
+            eprintln!("ignoring unusable curve in {}", path.display());
+            None
+        }
+    }
+}
+
+// the saved one, or a line from dark to max_lux if there isn't one
+async fn initial_curve(path: &Path, mapping: Mapping, max_lux: f32) -> Result<Spline<f32, f32>> {
+    if let Some(mut curve) = load_curve(path, mapping).await {
+        if curve.stretch(mapping.encode_lux(max_lux)) {
+            save_curve(path, &curve, mapping).await?;
+        }
+        return Ok(curve);
+    }
+    let floor = Key::new(
+        mapping.encode_lux(0.),
+        mapping.encode_brightness(1.),
+        Interpolation::default(),
+    );
+    let ceil = Key::new(
+        mapping.encode_lux(max_lux),
+        mapping.encode_brightness(mapping.max),
+        Interpolation::default(),
+    );
+    let curve = Spline::from_vec(vec![floor, ceil]);
+    // save right away so the gui has something to show
+    save_curve(path, &curve, mapping).await?;
+    Ok(curve)
+}
+
+async fn save_curve(path: &Path, curve: &Spline<f32, f32>, mapping: Mapping) -> Result<()> {
+    create_dir_all(path.parent().unwrap()).await?;
+    let keys = curve
+        .keys()
+        .iter()
+        .map(|key| {
+            // round off what going through the mapping adds
+            (
+                (mapping.decode_lux(key.t) * 100.).round() / 100.,
+                mapping.decode_brightness(key.value).round(),
+            )
+        })
+        .collect();
+    write(path, toml_edit::easy::to_string(&SavedCurve { keys })?)
+        .await
+        .with_context(|| format!("couldn't save curve to {}", path.display()))?;
+    Ok(())
+}
+
+async fn reset_curve(path: &Path) -> Result<()> {
+    match remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    lux: f32,
+    target: u32,
+    brightness: u32,
+    max_brightness: u32,
+    paused: bool,
+    offset: f64,
+}
+
+// what `ctl` can ask of the running fwtb-ab, one per line
+#[derive(Debug)]
+enum Command {
+    // for some minutes, or until resumed
+    Pause(Option<u64>),
+    Resume,
+    // added to the target, as a fraction of the max brightness
+    Offset(f32),
+    Status,
+    Stats,
+    ResetCurve,
+    // after the gui's edited it
+    ReloadCurve,
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (Some("pause"), None) => Command::Pause(None),
+            (Some("pause"), Some(minutes)) => Command::Pause(Some(
+                minutes
+                    .parse()
+                    .with_context(|| format!("{} isn't a number of minutes", minutes))?,
+            )),
+            (Some("resume"), None) => Command::Resume,
+            (Some("offset"), Some(offset)) => {
+                let offset: f32 = offset
+                    .parse()
+                    .with_context(|| format!("{} isn't a number", offset))?;
+                if !(-1. ..=1.).contains(&offset) {
+                    bail!("offset is a fraction of the max brightness, from -1 to 1");
+                }
+                Command::Offset(offset)
+            }
+            (Some("status"), None) => Command::Status,
+            (Some("stats"), None) => Command::Stats,
+            (Some("reset-curve"), None) => Command::ResetCurve,
+            (Some("reload-curve"), None) => Command::ReloadCurve,
+            _ => bail!(
+                "expected pause [minutes], resume, offset <fraction>, status, stats, reset-curve or reload-curve"
+            ),
+        };
+        if words.next().is_some() {
+            bail!("too many arguments");
+        }
+        Ok(command)
+    }
+}
+
+// counted as we go, for `ctl stats`
+struct Stats {
+    wakeups: AtomicU64,
+    sensor_reads: AtomicU64,
+    backlight_reads: AtomicU64,
+    backlight_writes: AtomicU64,
+    // when and where the counts were at the last report
+    last: Mutex<Option<(Instant, [u64; 4])>>,
+}
+
+// per second since the last report
+#[derive(Serialize)]
+struct Rates {
+    seconds: f64,
+    wakeups: f64,
+    sensor_reads: f64,
+    backlight_reads: f64,
+    backlight_writes: f64,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            wakeups: AtomicU64::new(0),
+            sensor_reads: AtomicU64::new(0),
+            backlight_reads: AtomicU64::new(0),
+            backlight_writes: AtomicU64::new(0),
+            last: Mutex::new(None),
+        }
+    }
+
+    fn counts(&self) -> [u64; 4] {
+        [
+            &self.wakeups,
+            &self.sensor_reads,
+            &self.backlight_reads,
+            &self.backlight_writes,
+        ]
+        .map(|count| count.load(Ordering::Relaxed))
+    }
+
+    fn start(&self) {
+        *self.last.lock().unwrap() = Some((Instant::now(), self.counts()));
+    }
+
+    fn report(&self) -> Rates {
+        let now = (Instant::now(), self.counts());
+        let (then, before) = self.last.lock().unwrap().replace(now).unwrap_or(now);
+        let seconds = now.0.duration_since(then).as_secs_f64();
+        let rate = |i: usize| {
+            let rate = (now.1[i] - before[i]) as f64 / seconds.max(f64::EPSILON);
+            (rate * 100.).round() / 100.
+        };
+        Rates {
+            seconds: (seconds * 10.).round() / 10.,
+            wakeups: rate(0),
+            sensor_reads: rate(1),
+            backlight_reads: rate(2),
+            backlight_writes: rate(3),
+        }
+    }
+}
+
+async fn nap(d: Duration) {
+    sleep(d).await;
+    STATS.wakeups.fetch_add(1, Ordering::Relaxed);
+}
+
+// reads a nonblocking device once it has something. tokio::fs would park a
+// blocking thread in read() instead, and shutdown waits for those to return
+async fn read_ready(fd: &AsyncFd<std::fs::File>, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        let mut ready = fd.readable().await?;
+        if let Ok(read) = ready.try_io(|fd| fd.get_ref().read(buf)) {
+            return read;
+        }
+    }
+}
+
+// one command per connection, answered with a line or the status
+async fn listen(commands: mpsc::Sender<(Command, oneshot::Sender<String>)>) -> Result<()> {
+    let Some(path) = socket_path() else {
+        eprintln!("no runtime dir, not listening for commands");
+        return Ok(());
+    };
+    create_dir_all(path.parent().unwrap()).await?;
+    // left behind if we didn't get to exit cleanly
+    let _ = remove_file(&path).await;
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("couldn't listen on {}: {}", path.display(), e);
+            return Ok(());
+        }
+    };
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let commands = commands.clone();
+        spawn(async move {
+            let (read, mut write) = stream.into_split();
+            let mut line = String::new();
+            BufReader::new(read).read_line(&mut line).await?;
+            let reply = match line.parse() {
+                Ok(command) => {
+                    let (reply, replied) = oneshot::channel();
+                    commands.send((command, reply)).await?;
+                    replied.await?
+                }
+                Err(e) => format!("error: {:#}\n", e),
+            };
+            write.write_all(reply.as_bytes()).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+    }
+}
+
+async fn send_command(command: &str) -> Result<String> {
+    let path = socket_path().context("no runtime dir to find fwtb-ab in")?;
+    let mut stream = UnixStream::connect(&path).await.with_context(|| {
+        format!(
+            "couldn't connect to {}, is fwtb-ab running?",
+            path.display()
+        )
+    })?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    if let Some(e) = reply.strip_prefix("error: ") {
+        bail!("{}", e.trim_end());
+    }
+    Ok(reply)
+}
+
+fn socket_path() -> Option<PathBuf> {
+    let mut path = dirs::runtime_dir()?;
+    path.push("fwtb");
+    path.push("fwtb-ab.sock");
+    Some(path)
+}
+
+async fn read_value<T>(path: impl AsRef<Path>) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let path = path.as_ref();
+    Ok(read_to_string(path)
+        .await
+        .with_context(|| format!("couldn't read {}", path.display()))?
+        .trim()
+        .parse()?)
+}
+
+// the first and last keys anchor the curve to the sensor's range
+trait Monotonic<T, U> {
+    fn monotonic_add(&mut self, k: T, v: U);
+    // adds a key at k level with the last if it's past it, returns if it did
+    fn stretch(&mut self, k: T) -> bool;
+}
+
+impl Monotonic<f32, f32> for Spline<f32, f32> {
+    fn monotonic_add(&mut self, k: f32, v: f32) {
+        // check if key exists and update or add new key
+        if let Some(key) = self.keys().iter().position(|&key| key.t == k) {
+            *self.get_mut(key).unwrap().value = v;
+        } else {
+            let k = Key::new(k, v, Interpolation::default());
+            self.add(k);
+        }
+
+        // make keys with values in the wrong direction consistent
+        let last = self.len() - 1;
+        if let Some(idx) = self.keys().iter().enumerate().position(|(i, &key)| {
+            (i != 0 && i != last) && ((key.value > v && key.t < k) || (key.value < v && key.t > k))
         }) {
             *self.get_mut(idx).unwrap().value = v;
         }
     }
+
+    fn stretch(&mut self, k: f32) -> bool {
+        match self.keys().last() {
+            // rather than moving it, which would rescale the segment before it
+            Some(&key) if key.t < k => {
+                self.add(Key::new(k, key.value, key.interpolation));
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_backlight(root: &Path, name: &str, kind: &str) {
+        let dir = root.join("class/backlight").join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("type"), format!("{}\n", kind)).unwrap();
+        std::fs::write(dir.join("brightness"), "100\n").unwrap();
+        std::fs::write(dir.join("max_brightness"), "255\n").unwrap();
+    }
+
+    #[tokio::test]
+    async fn finds_backlight_by_type_then_name() {
+        let root = tempfile::tempdir().unwrap();
+        fake_backlight(root.path(), "acpi_video0", "firmware");
+        fake_backlight(root.path(), "amdgpu_bl1", "raw");
+        fake_backlight(root.path(), "amdgpu_bl0", "raw");
+        let mut config = Config {
+            sysfs_root: root.path().to_owned(),
+            ..Config::default()
+        };
+
+        let found = Backlight::find(&config).await.unwrap();
+        assert_eq!(found, root.path().join("class/backlight/amdgpu_bl0"));
+
+        config.device = Some("acpi_video0".into());
+        let found = Backlight::find(&config).await.unwrap();
+        assert_eq!(found, root.path().join("class/backlight/acpi_video0"));
+
+        config.device = Some("intel_backlight".into());
+        assert!(Backlight::find(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn finds_light_sensor() {
+        let root = tempfile::tempdir().unwrap();
+        let bus = root.path().join("bus/iio/devices");
+        for (name, attr) in [
+            ("iio:device0", "in_accel_x_raw"),
+            ("iio:device1", "in_illuminance_raw"),
+        ] {
+            std::fs::create_dir_all(bus.join(name)).unwrap();
+            std::fs::write(bus.join(name).join(attr), "0\n").unwrap();
+        }
+        std::fs::write(bus.join("iio:device1/name"), "als\n").unwrap();
+        let mut config = Config {
+            sysfs_root: root.path().to_owned(),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            Sensor::find(&config).await.unwrap(),
+            bus.join("iio:device1")
+        );
+        config.sensor = Some("hid-sensor-als".into());
+        assert!(Sensor::find(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn replays_trace() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = dir.path().join("trace.csv");
+        // dark, then a lamp's switched on, then a flicker that shouldn't count
+        let mut lines = String::from("# max_brightness 255\nms,lux,target,brightness\n");
+        for (ms, lux) in [
+            (0, 10.),
+            (500, 10.),
+            (1000, 400.),
+            (1500, 400.),
+            (2000, 400.),
+        ] {
+            lines.push_str(&format!("{},{},40,40\n", ms, lux));
+        }
+        for ms in (2500..=6000).step_by(500) {
+            let lux = if ms == 4000 { 20. } else { 400. };
+            lines.push_str(&format!("{},{},40,40\n", ms, lux));
+        }
+        std::fs::write(&trace, lines).unwrap();
+        let curve = dir.path().join("curve.toml");
+        std::fs::write(&curve, "keys = [[0.0, 10.0], [1000.0, 255.0]]\n").unwrap();
+        let config = Config {
+            replay: Some(trace),
+            curve: curve.clone(),
+            window: 4,
+            ..Config::default()
+        };
+
+        let mut out = Vec::new();
+        replay(config, &mut out).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let brightness: Vec<&str> = out
+            .lines()
+            .skip(2)
+            .map(|line| line.rsplit(',').next().unwrap())
+            .collect();
+        // the curve's only extended flat out to the sensor's max, 3355 lux, so
+        // 400 is still 108 as in the file. brightening waits out
+        // brighten_delay_ms and is partway there at 3500, and the flicker's too
+        // short to dim for
+        let expected = [
+            "40", "12", "12", "12", "12", "12", "12", "74", "108", "108", "108", "108", "108",
+        ];
+        assert_eq!(brightness, expected, "{}", out);
+        // the learned curve is only read
+        assert_eq!(
+            std::fs::read_to_string(&curve).unwrap(),
+            "keys = [[0.0, 10.0], [1000.0, 255.0]]\n"
+        );
+    }
+
+    #[test]
+    fn stretching_keeps_the_curve() {
+        let mut curve = Spline::from_vec(vec![
+            Key::new(0., 10., Interpolation::default()),
+            Key::new(1000., 255., Interpolation::default()),
+        ]);
+        assert!(curve.stretch(3000.));
+        assert!(!curve.stretch(2000.));
+        let keys: Vec<_> = curve.keys().iter().map(|key| (key.t, key.value)).collect();
+        assert_eq!(keys, [(0., 10.), (1000., 255.), (3000., 255.)]);
+    }
+
+    #[test]
+    fn ddc_checksum_matches_spec() {
+        // the spec's own example, a brightness get from the host
+        let request = [0x51, 0x82, 0x01, VCP_BRIGHTNESS];
+        assert_eq!(ddc_checksum(DDC_ADDR << 1, &request), 0xac);
+    }
+
+    #[test]
+    fn ddc_brightness_round_trips() {
+        let mut ddc = Ddc {
+            bus: Box::new(FakeMonitor::new(100)),
+            wait: Duration::ZERO,
+        };
+        assert_eq!(ddc.get().unwrap(), (100, 100));
+        ddc.set(42).unwrap();
+        assert_eq!(ddc.get().unwrap(), (42, 100));
+        // the monitor holds it to its max
+        ddc.set(300).unwrap();
+        assert_eq!(ddc.get().unwrap(), (100, 100));
+    }
+
+    // a monitor on a noisy bus
+    struct Garbled(FakeMonitor);
+
+    impl I2c for Garbled {
+        fn write(&mut self, data: &[u8]) -> Result<()> {
+            self.0.write(data)
+        }
+
+        fn read(&mut self, data: &mut [u8]) -> Result<()> {
+            self.0.read(data)?;
+            data[9] ^= 0x04;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ddc_checksums_are_checked() {
+        let mut ddc = Ddc {
+            bus: Box::new(Garbled(FakeMonitor::new(100))),
+            wait: Duration::ZERO,
+        };
+        assert!(ddc.get().is_err());
+
+        let mut monitor = FakeMonitor::new(100);
+        let mut message = vec![0x51, 0x84, 0x03, VCP_BRIGHTNESS, 0, 42];
+        message.push(ddc_checksum(DDC_ADDR << 1, &message) ^ 1);
+        assert!(monitor.write(&message).is_err());
+        assert_eq!(monitor.brightness, 100);
+    }
+
+    #[tokio::test]
+    async fn battery_ignores_peripherals_and_unreadable_supplies() {
+        let root = tempfile::tempdir().unwrap();
+        let class = root.path().join("class/power_supply");
+        for (name, attrs) in [
+            ("ACAD", &[("type", "Mains"), ("online", "0")][..]),
+            ("BAT1", &[("type", "Battery"), ("capacity", "20")]),
+            // a mouse running flat isn't a reason to dim
+            (
+                "hid-mouse",
+                &[("type", "Battery"), ("scope", "Device"), ("capacity", "5")],
+            ),
+            // no online, e.g. a ucsi port that's gone away
+            ("ucsi-source-psy", &[("type", "USB")]),
+            ("AC2", &[("type", "Mains")]),
+        ] {
+            std::fs::create_dir_all(class.join(name)).unwrap();
+            for (attr, value) in attrs {
+                std::fs::write(class.join(name).join(attr), format!("{}\n", value)).unwrap();
+            }
+        }
+        let mut battery = Battery::new(&Config {
+            sysfs_root: root.path().to_owned(),
+            battery_threshold: 10,
+            ..Config::default()
+        });
+
+        assert!(!battery.check().await.unwrap());
+        battery.threshold = 30;
+        assert!(battery.check().await.unwrap());
+        std::fs::write(class.join("ACAD/online"), "1\n").unwrap();
+        assert!(!battery.check().await.unwrap());
+    }
 }
@@ -1,13 +1,27 @@
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
 use tokio::{
     io::{self, AsyncBufReadExt, BufReader},
     time::{sleep, Instant},
 };
 
+// same layout as fwtb.toml, see fwtbd.service
+const SYSTEM_CONFIG: &str = "/etc/fwtb/config.toml";
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    if let Some(arg) = args.next() {
+        if arg != "--defaults" {
+            bail!("unknown argument {}", arg);
+        }
+        let path = args.next().unwrap_or_else(|| String::from(SYSTEM_CONFIG));
+        return apply_defaults(Path::new(&path));
+    }
+
     let stdin = io::stdin();
     let reader = BufReader::new(stdin);
     let mut input = reader.lines();
@@ -44,6 +58,116 @@ async fn main() -> Result<()> {
     }
 }
 
+// the parts of fwtb.toml fwtbd can set, anything missing is left as is
+#[derive(Deserialize)]
+struct Defaults {
+    battery_limit: Option<u8>,
+    fan_auto: Option<bool>,
+    fan_duty: Option<u8>,
+    led_power: Option<String>,
+    led_left: Option<String>,
+    led_right: Option<String>,
+}
+
+impl Defaults {
+    fn read(path: &Path) -> Result<Self> {
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        toml_edit::easy::from_str(&s).with_context(|| format!("couldn't parse {}", path.display()))
+    }
+
+    // theirs wherever they've set one
+    fn under(self, theirs: Defaults) -> Self {
+        Self {
+            battery_limit: theirs.battery_limit.or(self.battery_limit),
+            fan_auto: theirs.fan_auto.or(self.fan_auto),
+            fan_duty: theirs.fan_duty.or(self.fan_duty),
+            led_power: theirs.led_power.or(self.led_power),
+            led_left: theirs.led_left.or(self.led_left),
+            led_right: theirs.led_right.or(self.led_right),
+        }
+    }
+}
+
+// run at boot, before anyone's logged in to open fwtb, which then applies
+// theirs on top. after resume someone likely is, so theirs goes on top here
+fn apply_defaults(path: &Path) -> Result<()> {
+    let mut defaults = Defaults::read(path)?;
+    if let Some(theirs) = user_config() {
+        match Defaults::read(&theirs) {
+            Ok(theirs) => defaults = defaults.under(theirs),
+            Err(e) => eprintln!("{:#}", e),
+        }
+    }
+    let mut commands = Vec::new();
+    if let Some(limit) = defaults.battery_limit {
+        commands.push(format!("fwchargelimit {}", limit));
+    }
+    match (defaults.fan_auto, defaults.fan_duty) {
+        (Some(true), _) => commands.push(String::from("autofanctrl")),
+        (_, Some(duty)) => commands.push(format!("fanduty {}", duty)),
+        _ => {}
+    }
+    for (led, color) in [
+        ("power", defaults.led_power),
+        ("left", defaults.led_left),
+        ("right", defaults.led_right),
+    ] {
+        if let Some(color) = color {
+            commands.push(format!("led {} {}", led, color));
+        }
+    }
+    // one that doesn't take shouldn't stop the rest
+    let mut failed = 0;
+    for command in commands {
+        if let Err(e) = try_ectool(&command) {
+            eprintln!("{:#}", e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} of the settings in {} weren't applied",
+            failed,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+// the first logged-in user's fwtb.toml, if they have one. /run/user has a
+// directory named by uid for everyone with a session
+fn user_config() -> Option<PathBuf> {
+    let logged_in: Vec<String> = std::fs::read_dir("/run/user")
+        .ok()?
+        .flatten()
+        .filter_map(|dir| dir.file_name().into_string().ok())
+        .collect();
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        let (uid, home) = (*fields.get(2)?, *fields.get(5)?);
+        let path = Path::new(home).join(".config/fwtb.toml");
+        (logged_in.iter().any(|id| id == uid) && path.exists()).then_some(path)
+    })
+}
+
+// like ectool(), but says what went wrong rather than panicking or ignoring it
+fn try_ectool(s: &str) -> Result<()> {
+    let output = std::process::Command::new("ectool")
+        .args(s.split_whitespace())
+        .output()
+        .context("couldn't run ectool")?;
+    if !output.status.success() {
+        bail!(
+            "ectool {} failed: {}",
+            s,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
 fn ectool(s: &str) {
     let a = s.split_whitespace();
     std::process::Command::new("ectool")
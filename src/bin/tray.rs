@@ -0,0 +1,697 @@
+#[path = "../backlight_service.rs"]
+mod backlight_service;
+#[path = "../config_file.rs"]
+mod config_file;
+#[path = "../config_watch.rs"]
+mod config_watch;
+
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+};
+use toml_edit::easy::value::Table;
+use zbus::{
+    dbus_interface,
+    zvariant::{OwnedObjectPath, OwnedValue, Value},
+    SignalContext,
+};
+
+use backlight_service::backlight_service;
+use config_file::{config_path, save_setting};
+use config_watch::ConfigWatch;
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::PathBuf,
+    process::{ChildStdin, Command, Stdio},
+    sync::{Arc, Mutex},
+};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+const WATCHER: &str = "org.kde.StatusNotifierWatcher";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let tray = Arc::new(Mutex::new(Tray {
+        settings: Settings::load(),
+        revision: 1,
+    }));
+    let daemon = Arc::new(Mutex::new(Daemon::default()));
+    let (conn, name) = serve(
+        zbus::ConnectionBuilder::session()?,
+        tray.clone(),
+        daemon.clone(),
+    )
+    .await
+    .context("couldn't connect to the session bus")?;
+
+    // the watcher can come and go with the panel, so register each time it's back
+    let dbus = zbus::fdo::DBusProxy::new(&conn).await?;
+    let mut watchers = dbus
+        .receive_name_owner_changed_with_args(&[(0, WATCHER)])
+        .await?;
+    if let Err(e) = register(&conn, &name).await {
+        eprintln!("no tray to show in yet, waiting for one: {}", e);
+    }
+
+    let mut config = ConfigWatch::new(&config_path())
+        .map_err(|e| {
+            eprintln!(
+                "can't watch {} for changes: {:#}",
+                config_path().display(),
+                e
+            )
+        })
+        .ok();
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    loop {
+        select! {
+            Some(changed) = watchers.next() => {
+                let args = changed.args()?;
+                if args.new_owner().is_some() {
+                    if let Err(e) = register(&conn, &name).await {
+                        eprintln!("couldn't register with the tray: {}", e);
+                    }
+                }
+            }
+            Some(()) = async {
+                match &mut config {
+                    Some(config) => config.changed().await.ok(),
+                    None => std::future::pending().await,
+                }
+            } => {
+                tray.lock().unwrap().settings = Settings::load();
+                refresh(&conn, &tray).await?;
+            }
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+    }
+    daemon.lock().unwrap().exit();
+    Ok(())
+}
+
+// puts the item and its menu on the bus, under the name to register
+async fn serve(
+    builder: zbus::ConnectionBuilder<'_>,
+    tray: Arc<Mutex<Tray>>,
+    daemon: Arc<Mutex<Daemon>>,
+) -> zbus::Result<(zbus::Connection, String)> {
+    let name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+    let conn = builder
+        .name(name.as_str())?
+        .serve_at(
+            ITEM_PATH,
+            Item {
+                tray: tray.clone(),
+                daemon: daemon.clone(),
+            },
+        )?
+        .serve_at(MENU_PATH, Menu { tray, daemon })?
+        .build()
+        .await?;
+    Ok((conn, name))
+}
+
+async fn register(conn: &zbus::Connection, name: &str) -> zbus::Result<()> {
+    conn.call_method(
+        Some(WATCHER),
+        "/StatusNotifierWatcher",
+        Some(WATCHER),
+        "RegisterStatusNotifierItem",
+        &(name),
+    )
+    .await?;
+    Ok(())
+}
+
+// lets the tray know the tooltip and menu are out of date
+async fn refresh(conn: &zbus::Connection, tray: &Mutex<Tray>) -> zbus::Result<()> {
+    let revision = {
+        let mut tray = tray.lock().unwrap();
+        tray.revision += 1;
+        tray.revision
+    };
+    let item = SignalContext::new(conn, ITEM_PATH)?;
+    Item::new_title(&item).await?;
+    Item::new_tool_tip(&item).await?;
+    Menu::layout_updated(&SignalContext::new(conn, MENU_PATH)?, revision, 0).await?;
+    Ok(())
+}
+
+// what the tray shows and changes, from fwtb.toml with fwtb's defaults
+#[derive(Deserialize)]
+#[serde(default)]
+struct Settings {
+    battery_limit: u8,
+    fan_duty: u8,
+    fan_auto: bool,
+    backlight_auto: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            battery_limit: 69,
+            fan_duty: 42,
+            fan_auto: true,
+            backlight_auto: true,
+        }
+    }
+}
+
+impl Settings {
+    // the user's over the system-wide ones, as fwtb reads them
+    fn load() -> Self {
+        let mut table = Table::new();
+        for path in [PathBuf::from("/etc/fwtb/config.toml"), config_path()] {
+            let Ok(s) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            match toml_edit::easy::from_str::<Table>(&s) {
+                Ok(file) => table.extend(file),
+                Err(e) => eprintln!("couldn't read {}: {}", path.display(), e),
+            }
+        }
+        toml_edit::easy::Value::Table(table)
+            .try_into()
+            .unwrap_or_else(|e| {
+                eprintln!("couldn't read settings: {}", e);
+                Self::default()
+            })
+    }
+
+    // in fwtb.toml in place, fwtb and fwtb-ab pick it up from there
+    fn save(key: &str, value: bool) -> Result<()> {
+        let path = config_path();
+        save_setting(&path, key, value).with_context(|| format!("couldn't save {}", path.display()))
+    }
+}
+
+struct Tray {
+    settings: Settings,
+    // of the menu layout
+    revision: u32,
+}
+
+// fwtbd, only asked for once something needs it
+#[derive(Default)]
+struct Daemon(Option<ChildStdin>);
+
+impl Daemon {
+    fn write(&mut self, command: &str) {
+        if self.0.is_none() {
+            self.0 = Command::new("pkexec")
+                .arg("fwtbd")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .map_err(|e| eprintln!("couldn't start fwtbd: {}", e))
+                .ok()
+                .and_then(|mut daemon| daemon.stdin.take());
+        }
+        let Some(daemon) = &mut self.0 else {
+            return;
+        };
+        if writeln!(daemon, "{}", command).is_err() {
+            eprintln!("fwtbd went away");
+            self.0 = None;
+        }
+    }
+
+    fn exit(&mut self) {
+        // fwtbd only stops when told to, see Toolbox::exit in fwtb
+        if self.0.is_some() {
+            self.write("exit");
+        }
+    }
+}
+
+impl Tray {
+    fn tool_tip(&self) -> String {
+        let settings = &self.settings;
+        format!(
+            "Charge limit: {}%\nFan: {}\nBacklight: {}",
+            settings.battery_limit,
+            if settings.fan_auto {
+                String::from("Auto")
+            } else {
+                format!("{}%", settings.fan_duty)
+            },
+            if settings.backlight_auto {
+                "Auto"
+            } else {
+                "Manual"
+            }
+        )
+    }
+
+    fn entries(&self) -> Vec<Entry> {
+        let settings = &self.settings;
+        vec![
+            Entry::Label(format!("Charge limit: {}%", settings.battery_limit)),
+            Entry::Action(Action::ChargeOnce, "Charge to full once"),
+            Entry::Separator,
+            Entry::Toggle(Action::FanAuto, "Automatic fan", settings.fan_auto),
+            Entry::Toggle(
+                Action::BacklightAuto,
+                "Automatic brightness",
+                settings.backlight_auto,
+            ),
+            Entry::Separator,
+            Entry::Action(Action::Open, "Open Framework Toolbox"),
+        ]
+    }
+}
+
+// on a thread of its own, as pkexec, systemctl and fwtb take a while to
+// start, and the tray's only locked to flip a setting so the menu still answers
+async fn act(tray: &Arc<Mutex<Tray>>, daemon: &Arc<Mutex<Daemon>>, action: Action) {
+    let (tray, daemon) = (tray.clone(), daemon.clone());
+    match tokio::task::spawn_blocking(move || run(&tray, &daemon, action)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => eprintln!("{:#}", e),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+fn run(tray: &Mutex<Tray>, daemon: &Mutex<Daemon>, action: Action) -> Result<()> {
+    match action {
+        Action::ChargeOnce => daemon.lock().unwrap().write("fwchargelimit 100 once"),
+        Action::FanAuto => {
+            let (value, duty) = {
+                let settings = &mut tray.lock().unwrap().settings;
+                settings.fan_auto = !settings.fan_auto;
+                (settings.fan_auto, settings.fan_duty)
+            };
+            if value {
+                daemon.lock().unwrap().write("autofanctrl");
+            } else {
+                daemon.lock().unwrap().write(&format!("fanduty {}", duty));
+            }
+            Settings::save("fan_auto", value)?;
+        }
+        Action::BacklightAuto => {
+            let value = {
+                let settings = &mut tray.lock().unwrap().settings;
+                settings.backlight_auto = !settings.backlight_auto;
+                settings.backlight_auto
+            };
+            // fwtb-ab reads it as it starts, and stops straight away if it's off
+            Settings::save("backlight_auto", value)?;
+            backlight_service(if value { "enable" } else { "disable" });
+        }
+        Action::Open => {
+            let mut fwtb = tokio::process::Command::new("fwtb")
+                .spawn()
+                .context("couldn't start fwtb")?;
+            // reaped once it's closed, we're around for a lot longer
+            tokio::spawn(async move { fwtb.wait().await });
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    ChargeOnce,
+    FanAuto,
+    BacklightAuto,
+    Open,
+}
+
+enum Entry {
+    Label(String),
+    Action(Action, &'static str),
+    Toggle(Action, &'static str, bool),
+    Separator,
+}
+
+impl Entry {
+    fn action(&self) -> Option<Action> {
+        match self {
+            Entry::Action(action, _) | Entry::Toggle(action, _, _) => Some(*action),
+            _ => None,
+        }
+    }
+
+    // see com.canonical.dbusmenu for what these mean
+    fn properties(&self) -> HashMap<String, OwnedValue> {
+        let mut properties = HashMap::new();
+        let mut set = |key: &str, value: Value| {
+            properties.insert(String::from(key), value.into());
+        };
+        match self {
+            Entry::Label(label) => {
+                set("label", Value::from(label.as_str()));
+                set("enabled", Value::from(false));
+            }
+            Entry::Action(_, label) => set("label", Value::from(*label)),
+            Entry::Toggle(_, label, on) => {
+                set("label", Value::from(*label));
+                set("toggle-type", Value::from("checkmark"));
+                set("toggle-state", Value::from(*on as i32));
+            }
+            Entry::Separator => set("type", Value::from("separator")),
+        }
+        properties
+    }
+}
+
+// id, properties, children
+type Layout = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+// icon name, icon pixmaps, title, text
+type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>, String, String);
+
+struct Item {
+    tray: Arc<Mutex<Tray>>,
+    daemon: Arc<Mutex<Daemon>>,
+}
+
+#[dbus_interface(name = "org.kde.StatusNotifierItem")]
+impl Item {
+    async fn activate(&self, _x: i32, _y: i32) {
+        act(&self.tray, &self.daemon, Action::Open).await;
+    }
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    fn context_menu(&self, _x: i32, _y: i32) {}
+
+    fn scroll(&self, _delta: i32, _orientation: String) {}
+
+    #[dbus_interface(property)]
+    fn category(&self) -> String {
+        String::from("Hardware")
+    }
+
+    #[dbus_interface(property)]
+    fn id(&self) -> String {
+        String::from("fwtb")
+    }
+
+    #[dbus_interface(property)]
+    fn title(&self) -> String {
+        String::from("Framework Toolbox")
+    }
+
+    #[dbus_interface(property)]
+    fn status(&self) -> String {
+        String::from("Active")
+    }
+
+    #[dbus_interface(property)]
+    fn icon_name(&self) -> String {
+        String::from("battery")
+    }
+
+    #[dbus_interface(property)]
+    fn tool_tip(&self) -> ToolTip {
+        let text = self.tray.lock().unwrap().tool_tip();
+        (
+            String::from("battery"),
+            Vec::new(),
+            String::from("Framework Toolbox"),
+            text,
+        )
+    }
+
+    #[dbus_interface(property)]
+    fn item_is_menu(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn menu(&self) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(MENU_PATH).unwrap()
+    }
+
+    #[dbus_interface(signal)]
+    async fn new_title(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn new_tool_tip(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+// the menu's flat, so ids are just the index plus one, 0 being the root
+struct Menu {
+    tray: Arc<Mutex<Tray>>,
+    daemon: Arc<Mutex<Daemon>>,
+}
+
+impl Menu {
+    fn entry(&self, id: i32) -> Option<Entry> {
+        let mut entries = self.tray.lock().unwrap().entries();
+        let i = usize::try_from(id).ok()?.checked_sub(1)?;
+        (i < entries.len()).then(|| entries.swap_remove(i))
+    }
+
+    async fn clicked(&self, conn: &zbus::Connection, id: i32) {
+        let Some(action) = self.entry(id).and_then(|entry| entry.action()) else {
+            return;
+        };
+        act(&self.tray, &self.daemon, action).await;
+        if let Err(e) = refresh(conn, &self.tray).await {
+            eprintln!("couldn't update the tray: {}", e);
+        }
+    }
+}
+
+#[dbus_interface(name = "com.canonical.dbusmenu")]
+impl Menu {
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        _recursion_depth: i32,
+        _property_names: Vec<String>,
+    ) -> zbus::fdo::Result<(u32, Layout)> {
+        let tray = self.tray.lock().unwrap();
+        let entries = tray.entries();
+        let children = match parent_id {
+            0 => (1..=entries.len() as i32)
+                .zip(&entries)
+                .map(|(id, entry)| {
+                    let layout: Layout = (id, entry.properties(), Vec::new());
+                    OwnedValue::from(Value::from(layout))
+                })
+                .collect(),
+            id if id > 0 && id as usize <= entries.len() => Vec::new(),
+            id => return Err(zbus::fdo::Error::InvalidArgs(format!("no item {}", id))),
+        };
+        let mut properties = HashMap::new();
+        if parent_id == 0 {
+            properties.insert(
+                String::from("children-display"),
+                Value::from("submenu").into(),
+            );
+        } else {
+            properties = entries[parent_id as usize - 1].properties();
+        }
+        Ok((tray.revision, (parent_id, properties, children)))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        _property_names: Vec<String>,
+    ) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.entry(id)?.properties())))
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: String) -> zbus::fdo::Result<OwnedValue> {
+        self.entry(id)
+            .and_then(|entry| entry.properties().remove(&name))
+            .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("no {} for item {}", name, id)))
+    }
+
+    async fn event(
+        &self,
+        #[zbus(connection)] conn: &zbus::Connection,
+        id: i32,
+        event_id: String,
+        _data: OwnedValue,
+        _timestamp: u32,
+    ) {
+        if event_id == "clicked" {
+            self.clicked(conn, id).await;
+        }
+    }
+
+    async fn event_group(
+        &self,
+        #[zbus(connection)] conn: &zbus::Connection,
+        events: Vec<(i32, String, OwnedValue, u32)>,
+    ) -> Vec<i32> {
+        let mut unknown = Vec::new();
+        for (id, event_id, _data, _timestamp) in events {
+            if self.entry(id).is_none() {
+                unknown.push(id);
+            } else if event_id == "clicked" {
+                self.clicked(conn, id).await;
+            }
+        }
+        unknown
+    }
+
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+        (Vec::new(), Vec::new())
+    }
+
+    #[dbus_interface(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[dbus_interface(property)]
+    fn text_direction(&self) -> String {
+        String::from("ltr")
+    }
+
+    #[dbus_interface(property)]
+    fn status(&self) -> String {
+        String::from("normal")
+    }
+
+    #[dbus_interface(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[dbus_interface(signal)]
+    async fn layout_updated(
+        ctxt: &SignalContext<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use tokio::sync::mpsc;
+
+    // stands in for the panel's
+    struct Watcher(mpsc::UnboundedSender<String>);
+
+    #[dbus_interface(name = "org.kde.StatusNotifierWatcher")]
+    impl Watcher {
+        fn register_status_notifier_item(&self, service: String) {
+            self.0.send(service).unwrap();
+        }
+    }
+
+    // a session bus of its own, stopped when the test's done
+    struct Bus(std::process::Child);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    #[tokio::test]
+    async fn registers_and_shows_the_menu() {
+        let Ok(mut child) = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            eprintln!("no dbus-daemon to test against");
+            return;
+        };
+        let mut address = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let _bus = Bus(child);
+        let address = address.trim();
+
+        let (registered, mut registrations) = mpsc::unbounded_channel();
+        let panel = zbus::ConnectionBuilder::address(address)
+            .unwrap()
+            .name(WATCHER)
+            .unwrap()
+            .serve_at("/StatusNotifierWatcher", Watcher(registered))
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let tray = Arc::new(Mutex::new(Tray {
+            settings: Settings {
+                battery_limit: 80,
+                fan_duty: 30,
+                fan_auto: false,
+                backlight_auto: true,
+            },
+            revision: 1,
+        }));
+        let (conn, name) = serve(
+            zbus::ConnectionBuilder::address(address).unwrap(),
+            tray,
+            Arc::default(),
+        )
+        .await
+        .unwrap();
+        register(&conn, &name).await.unwrap();
+        assert_eq!(registrations.recv().await.unwrap(), name);
+
+        // then the panel asks for what to show
+        let item = zbus::Proxy::new(
+            &panel,
+            name.as_str(),
+            ITEM_PATH,
+            "org.kde.StatusNotifierItem",
+        )
+        .await
+        .unwrap();
+        let (_, _, _, text): ToolTip = item.get_property("ToolTip").await.unwrap();
+        assert_eq!(text, "Charge limit: 80%\nFan: 30%\nBacklight: Auto");
+        let menu = zbus::Proxy::new(&panel, name.as_str(), MENU_PATH, "com.canonical.dbusmenu")
+            .await
+            .unwrap();
+        let (revision, (_, _, children)): (u32, Layout) = menu
+            .call("GetLayout", &(0, -1, Vec::<String>::new()))
+            .await
+            .unwrap();
+        assert_eq!(revision, 1);
+        let labels: Vec<_> = children
+            .into_iter()
+            .map(|child| {
+                let (_, properties, _) = Layout::try_from(child).unwrap();
+                properties
+                    .get("label")
+                    .map(|label| String::try_from(label.clone()).unwrap())
+            })
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                Some("Charge limit: 80%"),
+                Some("Charge to full once"),
+                None,
+                Some("Automatic fan"),
+                Some("Automatic brightness"),
+                None,
+                Some("Open Framework Toolbox"),
+            ]
+            .map(|label| label.map(String::from))
+        );
+    }
+}
@@ -0,0 +1,56 @@
+// shared by fwtb and fwtb-tray, which both write fwtb.toml
+
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+
+use toml_edit::{Document, Item, TableLike};
+
+pub fn config_path() -> PathBuf {
+    let mut path = dirs::config_dir().unwrap();
+    path.push("fwtb.toml");
+    path
+}
+
+// just the one key, for settings that take effect outside fwtb straight away
+pub fn save_setting(
+    path: &Path,
+    key: &str,
+    value: impl Into<toml_edit::Value>,
+) -> anyhow::Result<()> {
+    let mut doc: Document = match read_to_string(path) {
+        Ok(s) => s.parse()?,
+        Err(_) => Document::new(),
+    };
+    let mut new = Document::new();
+    new[key] = toml_edit::value(value);
+    merge_config(doc.as_table_mut(), new.as_table());
+    write(path, doc.to_string())?;
+    Ok(())
+}
+
+// new's keys into doc, keeping its comments and whatever else is in it
+pub fn merge_config(doc: &mut dyn TableLike, new: &dyn TableLike) {
+    for (key, item) in new.iter() {
+        let Some(old) = doc.get_mut(key) else {
+            // as a [section] rather than inline, like someone would write it
+            let item = match item.clone() {
+                Item::Value(toml_edit::Value::InlineTable(table)) => {
+                    Item::Table(table.into_table())
+                }
+                item => item,
+            };
+            doc.insert(key, item);
+            continue;
+        };
+        if let (Some(old), Some(new)) = (old.as_table_like_mut(), item.as_table_like()) {
+            merge_config(old, new);
+        } else if let (Some(old), Some(new)) = (old.as_value_mut(), item.as_value()) {
+            // keeps the comment after it
+            let decor = old.decor().clone();
+            *old = new.clone();
+            *old.decor_mut() = decor;
+        } else {
+            *old = item.clone();
+        }
+    }
+}
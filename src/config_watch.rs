@@ -0,0 +1,60 @@
+// shared by fwtb, fwtb-ab and fwtb-tray, which all follow fwtb.toml
+
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::FromRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use tokio::io::unix::AsyncFd;
+
+// fwtb.toml's directory, since editors and dotfile managers tend to replace
+// the file rather than write to it
+pub struct ConfigWatch {
+    // nonblocking, so it doesn't tie up a thread shutdown waits on
+    file: AsyncFd<File>,
+    name: OsString,
+}
+
+impl ConfigWatch {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        let dir = CString::new(path.parent().unwrap().as_os_str().as_bytes())?;
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            file: AsyncFd::new(file)?,
+            name: path.file_name().unwrap().to_owned(),
+        })
+    }
+
+    // returns once the file's been written or replaced
+    pub async fn changed(&mut self) -> anyhow::Result<()> {
+        let mut events = [0; 4096];
+        loop {
+            let mut ready = self.file.readable().await?;
+            let Ok(n) = ready.try_io(|file| file.get_ref().read(&mut events)) else {
+                continue;
+            };
+            let n = n?;
+            // struct inotify_event: wd, mask, cookie, len, then a padded name
+            let mut i = 0;
+            while i + 16 <= n {
+                let len = u32::from_ne_bytes(events[i + 12..i + 16].try_into().unwrap()) as usize;
+                let name = &events[i + 16..(i + 16 + len).min(n)];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                if name == self.name.as_bytes() {
+                    return Ok(());
+                }
+                i += 16 + len;
+            }
+        }
+    }
+}
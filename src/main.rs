@@ -1,26 +1,45 @@
-use std::fs::{read_to_string, File};
+mod backlight_service;
+mod config_file;
+mod config_watch;
+
+use std::collections::BTreeSet;
+use std::fs::{create_dir_all, read_to_string, remove_file, rename, write};
 use std::io::Write;
-use std::process::{Child, ChildStdin, Command, Stdio};
+use std::path::{Path as FilePath, PathBuf};
+use std::process::{ChildStdin, Command, Stdio};
+use std::time::Duration;
 
+use iced::widget::canvas::{self, event, Canvas, Cursor, Frame, Geometry, Path, Stroke};
 use iced::widget::{
-    button, column, container, horizontal_rule, horizontal_space, pick_list, row, slider, text,
-    toggler,
+    button, column, container, horizontal_rule, horizontal_space, pick_list, row, scrollable,
+    slider, text, text_input, toggler,
 };
 use iced::{
-    alignment, executor, Alignment, Application, Color, Element, Length, Settings, Subscription,
-    Theme,
+    alignment, executor, mouse, Alignment, Application, Color, Element, Length, Point, Rectangle,
+    Settings, Size, Subscription, Theme,
 };
 
 use iced_native::{window, Event};
 
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use toml_edit::easy::value::{Table, Value};
+use toml_edit::Document;
+
+use backlight_service::backlight_service;
+use config_file::{config_path, merge_config, save_setting};
+use config_watch::ConfigWatch;
+
+// bumped when the layout of fwtb.toml changes, with a step for it in migrate()
+const CONFIG_VERSION: i64 = 2;
 
 pub fn main() -> iced::Result {
     Toolbox::run(Settings {
         exit_on_close_request: false,
         window: iced::window::Settings {
-            size: (400, 500),
-            resizable: false,
+            size: (400, 720),
+            min_size: Some((400, 300)),
             ..iced::window::Settings::default()
         },
         ..Settings::default()
@@ -29,6 +48,7 @@ pub fn main() -> iced::Result {
 
 #[derive(Deserialize, Serialize)]
 struct Toolbox {
+    version: i64,
     battery_limit: u8,
     fan_duty: u8,
     fan_auto: bool,
@@ -36,18 +56,60 @@ struct Toolbox {
     led_power: Option<LedColor>,
     led_left: Option<LedColor>,
     led_right: Option<LedColor>,
+    autosave: bool,
+    // fwtb-ab's settings, kept as-is so saving doesn't drop them
+    #[serde(default)]
+    backlight: Table,
 
     #[serde(skip)]
-    backlight_daemon: Option<Child>,
+    curve: Vec<(f32, f32)>,
+    #[serde(skip)]
+    curve_dragging: bool,
+    // bumped whenever the curve's replaced under a drag, so it lets go
+    #[serde(skip)]
+    curve_revision: u64,
+    #[serde(skip)]
+    curve_file: String,
+    #[serde(skip)]
+    curve_note: String,
+    #[serde(skip)]
+    lux: Option<f32>,
+    #[serde(skip)]
+    max_brightness: Option<u32>,
+    #[serde(skip)]
+    backlight_paused: bool,
+    // percent of the max brightness
+    #[serde(skip)]
+    backlight_offset: i32,
     #[serde(skip)]
     daemon: Option<ChildStdin>,
     #[serde(skip)]
     should_exit: bool,
+    // what went wrong reading fwtb.toml, if anything
+    #[serde(skip)]
+    config_note: String,
+    // the settings as they are in fwtb.toml, to tell if anything's unsaved
+    #[serde(skip)]
+    saved: String,
+    // what /etc/fwtb/config.toml filled in, kept out of fwtb.toml unless changed
+    #[serde(skip)]
+    from_system: Table,
+    // asking whether to save before closing
+    #[serde(skip)]
+    closing: bool,
+    #[serde(skip)]
+    bundle_file: String,
+    // read and waiting on the preview being ok'd
+    #[serde(skip)]
+    bundle: Option<Bundle>,
+    #[serde(skip)]
+    bundle_note: String,
 }
 
 impl Default for Toolbox {
     fn default() -> Self {
         Toolbox {
+            version: CONFIG_VERSION,
             battery_limit: 69,
             fan_duty: 42,
             fan_auto: true,
@@ -55,9 +117,26 @@ impl Default for Toolbox {
             led_power: Some(LedColor::default()),
             led_left: Some(LedColor::default()),
             led_right: Some(LedColor::default()),
-            backlight_daemon: None,
+            autosave: false,
+            backlight: Default::default(),
+            curve: Vec::new(),
+            curve_dragging: false,
+            curve_revision: 0,
+            curve_file: String::new(),
+            curve_note: String::new(),
+            lux: None,
+            max_brightness: None,
+            backlight_paused: false,
+            backlight_offset: 0,
             daemon: None,
             should_exit: false,
+            config_note: String::new(),
+            saved: String::new(),
+            from_system: Table::new(),
+            closing: false,
+            bundle_file: String::new(),
+            bundle: None,
+            bundle_note: String::new(),
         }
     }
 }
@@ -70,11 +149,34 @@ pub enum Message {
     FanDutyChanged(u8),
     FanAutoToggled(bool),
     BacklightAutoToggled(bool),
+    BacklightPauseToggled(bool),
+    BacklightOffsetChanged(i32),
     LEDPowerSelected(LedColor),
     LEDLeftSelected(LedColor),
     LEDRightSelected(LedColor),
+    Tick,
+    // the command sent and what fwtb-ab said, None if it's not running or
+    // didn't like it
+    BacklightReplied(String, Option<String>),
+    // curve revision, key index, lux, brightness
+    CurveKeyDragged(u64, usize, f32, f32),
+    CurveKeyReleased,
+    CurveReset,
+    CurveFileChanged(String),
+    CurveImport,
+    CurveExport,
+    ConfigChanged,
+    AutosaveToggled(bool),
     // Apply,
     Save,
+    Revert,
+    Close(bool),
+    CloseCancelled,
+    BundleFileChanged(String),
+    BundleImport,
+    BundleApply,
+    BundleCancelled,
+    BundleExport,
 }
 
 impl Application for Toolbox {
@@ -108,20 +210,21 @@ impl Application for Toolbox {
         let daemon_stdin = daemon.stdin.take().expect("couldn't take stdin of daemon");
 
         // check for existing config, otherwise default
-        let mut tb: Toolbox;
-        let mut conf = dirs::config_dir().unwrap();
-        conf.push("fwtb.toml");
-        let mut from_conf = false;
-        match read_to_string(conf) {
-            Ok(s) => {
-                tb = toml_edit::easy::from_str(&s).unwrap_or_default();
-                from_conf = true;
-            }
-            Err(_) => {
-                tb = Toolbox::default();
-            }
-        }
+        let system = system_config();
+        let (loaded, config_note) = load_config(&config_path(), &system);
+        let from_conf = loaded.is_some();
+        // fwtbd.service already applied the system-wide ones if there's no config
+        let mut tb = loaded.unwrap_or_else(|| parse_config("", &system).unwrap_or_default());
+        tb.config_note = config_note;
+        tb.saved = tb.settings();
         tb.daemon = Some(daemon_stdin);
+        let mut curve_file = dirs::home_dir().unwrap();
+        curve_file.push("fwtb-curve.toml");
+        tb.curve_file = curve_file.display().to_string();
+        let mut bundle_file = dirs::home_dir().unwrap();
+        bundle_file.push("fwtb-bundle.toml");
+        tb.bundle_file = bundle_file.display().to_string();
+        let refresh = tb.refresh_backlight();
 
         if from_conf {
             daemon_write(tb.daemon.as_ref(), "fwchargelimit", tb.battery_limit);
@@ -142,14 +245,10 @@ impl Application for Toolbox {
         }
 
         if tb.backlight_auto {
-            tb.backlight_daemon = Some(
-                Command::new("fwtb-ab")
-                    .spawn()
-                    .expect("couldn't start autobacklight"),
-            )
+            backlight_service("start");
         }
 
-        (tb, iced::Command::none())
+        (tb, refresh)
     }
 
     // TODO remove this
@@ -157,6 +256,10 @@ impl Application for Toolbox {
         let subs = vec![
             // dunno why no closure here
             iced_native::subscription::events().map(Message::Event),
+            // pick up what fwtb-ab learned and where the sensor is at
+            iced::time::every(Duration::from_secs(1)).map(|_| Message::Tick),
+            // fwtb.toml edited by hand or a dotfile manager
+            watch_config(config_path()),
         ];
         iced_native::Subscription::batch(subs)
     }
@@ -185,16 +288,93 @@ impl Application for Toolbox {
             }
             Message::BacklightAutoToggled(value) => {
                 self.backlight_auto = value;
-                if self.backlight_auto {
-                    self.backlight_daemon = Some(
-                        Command::new("fwtb-ab")
-                            .spawn()
-                            .expect("couldn't start autobacklight"),
-                    )
-                } else if let Some(c) = &mut self.backlight_daemon {
-                    c.kill().expect("couldn't kill autobacklight");
+                // saved first, fwtb-ab reads it as it starts and stops if it's off
+                let conf = config_path();
+                if let Err(e) = save_setting(&conf, "backlight_auto", value) {
+                    self.config_note = format!("Couldn't save {}: {}", conf.display(), e);
+                }
+                backlight_service(if value { "enable" } else { "disable" });
+            }
+            Message::BacklightPauseToggled(value) => {
+                return self.backlight_command(if value { "pause" } else { "resume" });
+            }
+            Message::BacklightOffsetChanged(value) => {
+                self.backlight_offset = value;
+                return self.backlight_command(&format!("offset {}", value as f32 / 100.));
+            }
+            Message::Tick => {
+                // once a second so dragging a slider isn't a save per step
+                if self.autosave && self.dirty() {
+                    self.update(Message::Save);
+                }
+                return self.refresh_backlight();
+            }
+            Message::BacklightReplied(command, reply) => match (command.as_str(), reply) {
+                ("status", reply) => {
+                    let status =
+                        reply.and_then(|s| toml_edit::easy::from_str::<BacklightStatus>(&s).ok());
+                    match status {
+                        Some(status) if self.backlight_auto => {
+                            self.lux = Some(status.lux);
+                            self.max_brightness = Some(status.max_brightness);
+                            self.backlight_paused = status.paused;
+                            self.backlight_offset = (status.offset * 100.).round() as i32;
+                        }
+                        _ => self.lux = None,
+                    }
+                }
+                ("pause", Some(_)) => self.backlight_paused = true,
+                ("resume", Some(_)) => self.backlight_paused = false,
+                // fwtb-ab starts a fresh one, otherwise it will when it's started
+                ("reset-curve", None) => {
+                    let _ = remove_file(curve_path());
+                }
+                _ => {}
+            },
+            Message::CurveKeyDragged(revision, i, t, v) => {
+                if revision == self.curve_revision {
+                    if let Some(key) = self.curve.get_mut(i) {
+                        *key = (t, v);
+                        self.curve_dragging = true;
+                    }
+                }
+            }
+            Message::CurveKeyReleased => {
+                if !std::mem::take(&mut self.curve_dragging) {
+                    return iced::Command::none();
+                }
+                self.curve_note = match write_curve(&curve_path(), &self.curve) {
+                    Ok(()) => String::new(),
+                    Err(e) => format!("couldn't save curve: {}", e),
+                };
+                return self.backlight_command("reload-curve");
+            }
+            Message::CurveReset => {
+                self.set_curve(Vec::new());
+                self.curve_note = String::from("Curve reset");
+                return self.backlight_command("reset-curve");
+            }
+            Message::CurveFileChanged(value) => {
+                self.curve_file = value;
+            }
+            Message::CurveImport => {
+                let imported = read_curve(FilePath::new(&self.curve_file))
+                    .and_then(|keys| write_curve(&curve_path(), &keys).map(|_| keys));
+                match imported {
+                    Ok(keys) => {
+                        self.set_curve(keys);
+                        self.curve_note = format!("Imported {}", self.curve_file);
+                        return self.backlight_command("reload-curve");
+                    }
+                    Err(e) => self.curve_note = format!("couldn't import: {}", e),
                 }
             }
+            Message::CurveExport => {
+                self.curve_note = match write_curve(FilePath::new(&self.curve_file), &self.curve) {
+                    Ok(()) => format!("Exported to {}", self.curve_file),
+                    Err(e) => format!("couldn't export: {}", e),
+                };
+            }
             Message::LEDPowerSelected(value) => {
                 self.led_power = Some(value);
                 daemon_write(self.daemon.as_ref(), "led power", value);
@@ -207,29 +387,130 @@ impl Application for Toolbox {
                 self.led_right = Some(value);
                 daemon_write(self.daemon.as_ref(), "led right", value);
             }
+            Message::ConfigChanged => {
+                let conf = config_path();
+                // left as is if it's gone, and not moved aside if it's
+                // broken since it's probably still being edited
+                if let Ok(s) = read_to_string(&conf) {
+                    match parse_config(&s, &system_config()) {
+                        Ok(tb) => {
+                            self.config_note = file_note(&conf, &tb);
+                            let saved = tb.settings();
+                            let from_system = tb.from_system.clone();
+                            // only what changed there, edits here that aren't
+                            // saved yet are kept
+                            match self.with_changes(&tb) {
+                                Ok(tb) => self.reload(tb),
+                                Err(_) => self.reload(tb),
+                            }
+                            self.saved = saved;
+                            self.from_system = from_system;
+                        }
+                        Err(e) => {
+                            self.config_note = format!("Couldn't reload {}: {}", conf.display(), e)
+                        }
+                    }
+                }
+            }
+            Message::AutosaveToggled(value) => {
+                self.autosave = value;
+            }
             Message::Save => {
-                let toml = toml_edit::easy::to_string(&self).unwrap();
-                let mut conf = dirs::config_dir().unwrap();
-                conf.push("fwtb.toml");
-                let mut f = File::create(conf).unwrap();
-                f.write_all("# Generated file, DO NOT EDIT!\n".as_bytes())
-                    .unwrap();
-                f.write_all(toml.as_bytes()).unwrap();
+                let conf = config_path();
+                self.config_note = match save_config(&conf, self) {
+                    Ok(()) => {
+                        self.saved = self.settings();
+                        String::new()
+                    }
+                    Err(e) => format!("Couldn't save {}: {}", conf.display(), e),
+                };
+            }
+            Message::Revert => {
+                let conf = config_path();
+                // back to the defaults if it's never been saved
+                let saved = read_to_string(&conf).unwrap_or_default();
+                match parse_config(&saved, &system_config()) {
+                    Ok(tb) => {
+                        self.config_note = file_note(&conf, &tb);
+                        self.reload(tb);
+                        self.saved = self.settings();
+                    }
+                    Err(e) => {
+                        self.config_note = format!("Couldn't revert to {}: {}", conf.display(), e)
+                    }
+                }
+            }
+            Message::Close(save) => {
+                if save {
+                    self.update(Message::Save);
+                }
+                // stay open to say why if saving didn't work
+                if !save || !self.dirty() {
+                    self.exit();
+                }
+            }
+            Message::CloseCancelled => self.closing = false,
+            Message::BundleFileChanged(value) => {
+                self.bundle_file = value;
+            }
+            Message::BundleImport => match preview_bundle(FilePath::new(&self.bundle_file), self) {
+                Ok((_, changes)) if changes.is_empty() => {
+                    self.bundle = None;
+                    self.bundle_note = format!("{} matches what's here", self.bundle_file);
+                }
+                Ok((bundle, changes)) => {
+                    self.bundle = Some(bundle);
+                    self.bundle_note = changes.join("\n");
+                }
+                Err(e) => {
+                    self.bundle = None;
+                    self.bundle_note = format!("couldn't import: {}", e);
+                }
+            },
+            Message::BundleApply => {
+                let Some(bundle) = self.bundle.take() else {
+                    return iced::Command::none();
+                };
+                match Value::Table(bundle.settings).try_into::<Toolbox>() {
+                    Ok(tb) => self.reload(tb),
+                    Err(e) => {
+                        self.bundle_note = format!("couldn't import: {}", e);
+                        return iced::Command::none();
+                    }
+                }
+                self.bundle_note = format!("Imported {}", self.bundle_file);
+                // fwtb-ab only sees its settings once they're in fwtb.toml
+                self.update(Message::Save);
+                if bundle.curve.len() >= 2 {
+                    match write_curve(&curve_path(), &bundle.curve) {
+                        Ok(()) => {
+                            self.set_curve(bundle.curve);
+                            return self.backlight_command("reload-curve");
+                        }
+                        Err(e) => self.bundle_note = format!("couldn't import curve: {}", e),
+                    }
+                }
+            }
+            Message::BundleCancelled => {
+                self.bundle = None;
+                self.bundle_note = String::new();
+            }
+            Message::BundleExport => {
+                self.bundle_note = match export_bundle(FilePath::new(&self.bundle_file), self) {
+                    Ok(()) => format!("Exported to {}", self.bundle_file),
+                    Err(e) => format!("couldn't export: {}", e),
+                };
             }
             Message::Event(event) => {
-                // TODO
-                // fwtbd kills itself when stdin is dropped
-                // perhaps autobacklight should do the same so there's no resource leaks
-                // or find other non-hacky workaround
                 if let Event::Window(window::Event::CloseRequested) = event {
-                    if let Some(c) = &mut self.backlight_daemon {
-                        c.kill().expect("couldn't kill autobacklight");
+                    if self.autosave && self.dirty() {
+                        self.update(Message::Save);
                     }
-                    if let Some(c) = &mut self.daemon {
-                        // TODO temporary hack
-                        daemon_write(self.daemon.as_ref(), "exit", "");
+                    if self.dirty() {
+                        self.closing = true;
+                    } else {
+                        self.exit();
                     }
-                    self.should_exit = true;
                 }
             }
         };
@@ -323,15 +604,68 @@ impl Application for Toolbox {
         .text_alignment(alignment::Horizontal::Right)
         .spacing(space);
 
+        let backlight_pause_toggler = toggler(
+            String::from("Pause"),
+            self.backlight_paused,
+            Message::BacklightPauseToggled,
+        )
+        .text_alignment(alignment::Horizontal::Right)
+        .spacing(space);
+
+        let backlight_offset_row = row![
+            text(format!("Offset: {:+}%", self.backlight_offset)),
+            slider(
+                -50..=50,
+                self.backlight_offset,
+                Message::BacklightOffsetChanged
+            ),
+        ]
+        .align_items(Alignment::Center)
+        .spacing(space);
+
+        // match how fwtb-ab interpolates so straight lines here are straight there
+        let perceptual = self.backlight.get("perceptual").and_then(|v| v.as_bool());
+        let gamma = self.backlight.get("gamma").and_then(|v| v.as_float());
+        let backlight_curve = Canvas::new(CurvePlot {
+            keys: &self.curve,
+            revision: self.curve_revision,
+            lux: self.lux,
+            max_brightness: self.max_brightness,
+            gamma: perceptual
+                .unwrap_or(false)
+                .then_some(gamma.unwrap_or(2.2) as f32),
+        })
+        .width(Length::Fill)
+        .height(Length::Units(150));
+
+        let backlight_curve_row = row![
+            button("Reset").on_press(Message::CurveReset),
+            text_input("Curve file", &self.curve_file, Message::CurveFileChanged),
+            button("Import").on_press(Message::CurveImport),
+            button("Export").on_press(Message::CurveExport),
+        ]
+        .align_items(Alignment::Center)
+        .spacing(space);
+
         let backlight_controls = column![
             text(format!("Backlight: {}", {
-                if self.backlight_auto {
-                    "Auto".to_string()
+                if self.backlight_auto && self.backlight_paused {
+                    "Paused".to_string()
+                } else if self.backlight_auto {
+                    match self.lux {
+                        Some(lux) => format!("Auto ({:.0} lux)", lux),
+                        None => "Auto".to_string(),
+                    }
                 } else {
                     "Manual".to_string()
                 }
             })),
             backlight_auto_toggler,
+            backlight_pause_toggler,
+            backlight_offset_row,
+            backlight_curve,
+            backlight_curve_row,
+            text(&self.curve_note).size(16),
         ]
         .align_items(Alignment::Center)
         .spacing(space);
@@ -373,17 +707,85 @@ impl Application for Toolbox {
             .align_items(Alignment::Center)
             .spacing(space);
 
+        // Saving stuff
+        //
+        let save_controls: Element<_> = if self.closing {
+            column![
+                text("Save changes before closing?"),
+                row![
+                    button("Save").on_press(Message::Close(true)),
+                    button("Discard").on_press(Message::Close(false)),
+                    button("Cancel").on_press(Message::CloseCancelled),
+                ]
+                .spacing(space),
+            ]
+            .align_items(Alignment::Center)
+            .spacing(space)
+            .into()
+        } else {
+            // only something to do if there are unsaved changes
+            let mut save = button("Save");
+            let mut revert = button("Revert");
+            if self.dirty() {
+                save = save.on_press(Message::Save);
+                revert = revert.on_press(Message::Revert);
+            }
+            row![
+                toggler(
+                    String::from("Autosave"),
+                    self.autosave,
+                    Message::AutosaveToggled
+                )
+                .text_alignment(alignment::Horizontal::Right)
+                .spacing(space),
+                revert,
+                save,
+            ]
+            .align_items(Alignment::Center)
+            .spacing(space)
+            .into()
+        };
+
+        // Bundle stuff
+        //
+        let bundle_row = row![
+            text_input("Bundle file", &self.bundle_file, Message::BundleFileChanged),
+            button("Import").on_press(Message::BundleImport),
+            button("Export").on_press(Message::BundleExport),
+        ]
+        .align_items(Alignment::Center)
+        .spacing(space);
+
+        let bundle_controls = if self.bundle.is_some() {
+            // what importing would change, which can run long
+            column![
+                bundle_row,
+                scrollable(text(&self.bundle_note).size(16)).height(Length::Units(80)),
+                row![
+                    button("Apply").on_press(Message::BundleApply),
+                    button("Cancel").on_press(Message::BundleCancelled),
+                ]
+                .spacing(space),
+            ]
+        } else {
+            column![bundle_row, text(&self.bundle_note).size(16)]
+        }
+        .align_items(Alignment::Center)
+        .spacing(space);
+
         // Everything stuff
         //
         let content: Element<_> = column![
             title,
+            text(&self.config_note).size(16),
             horizontal_rule(5),
             horizontal_space(Length::Fill),
             battery_controls,
             fan_controls,
             backlight_controls,
             led_controls,
-            button("Save").on_press(Message::Save),
+            save_controls,
+            bundle_controls,
         ]
         .spacing(space)
         .padding(space)
@@ -391,7 +793,680 @@ impl Application for Toolbox {
         .into();
 
         // container(content.explain(Color::BLACK)).center_x().into()
-        container(content).center_x().into()
+        // scrolls once there's more than fits
+        scrollable(container(content).width(Length::Fill).center_x()).into()
+    }
+}
+
+impl Toolbox {
+    fn set_curve(&mut self, keys: Vec<(f32, f32)>) {
+        self.curve = keys;
+        self.curve_dragging = false;
+        self.curve_revision += 1;
+    }
+
+    // the status comes back as a BacklightReplied
+    fn refresh_backlight(&mut self) -> iced::Command<Message> {
+        if !self.curve_dragging {
+            if let Ok(keys) = read_curve(&curve_path()) {
+                if keys != self.curve {
+                    self.set_curve(keys);
+                }
+            }
+        }
+        self.backlight_command("status")
+    }
+
+    // sent off the ui thread, fwtb-ab can take a moment to answer
+    fn backlight_command(&self, command: &str) -> iced::Command<Message> {
+        let auto = self.backlight_auto;
+        let sent = command.to_owned();
+        let command = command.to_owned();
+        iced::Command::perform(
+            async move {
+                if !auto {
+                    return None;
+                }
+                match send_backlight_command(&sent).await {
+                    Ok(s) if s.starts_with("error: ") => {
+                        eprintln!("fwtb-ab: {}", s.trim_end());
+                        None
+                    }
+                    Ok(s) => Some(s),
+                    Err(_) => None,
+                }
+            },
+            move |reply| Message::BacklightReplied(command.clone(), reply),
+        )
+    }
+
+    // what'd be saved to fwtb.toml
+    fn settings(&self) -> String {
+        toml_edit::easy::to_string(self).unwrap_or_default()
+    }
+
+    fn dirty(&self) -> bool {
+        self.settings() != self.saved
+    }
+
+    fn exit(&mut self) {
+        // TODO
+        // fwtbd kills itself when stdin is dropped
+        // find other non-hacky workaround
+        // fwtb-ab is a service and keeps going without us
+        if self.daemon.is_some() {
+            // TODO temporary hack
+            daemon_write(self.daemon.as_ref(), "exit", "");
+        }
+        self.should_exit = true;
+    }
+
+    // ours, but with whatever's changed in tb since it was last saved or read
+    fn with_changes(&self, tb: &Toolbox) -> anyhow::Result<Toolbox> {
+        let saved: Table = toml_edit::easy::from_str(&self.saved)?;
+        let mut ours = to_table(self)?;
+        take_changes(&mut ours, &saved, &to_table(tb)?);
+        let mut merged = Value::Table(ours).try_into::<Toolbox>()?;
+        merged.from_system = tb.from_system.clone();
+        Ok(merged)
+    }
+
+    // applies only what's changed, through the same messages as the controls
+    fn reload(&mut self, tb: Toolbox) {
+        if tb.battery_limit != self.battery_limit {
+            self.update(Message::BatteryLimitChanged(tb.battery_limit));
+        }
+        if tb.fan_auto != self.fan_auto || (!tb.fan_auto && tb.fan_duty != self.fan_duty) {
+            self.fan_duty = tb.fan_duty;
+            self.update(Message::FanAutoToggled(tb.fan_auto));
+        }
+        self.fan_duty = tb.fan_duty;
+        let leds = [
+            (
+                tb.led_power,
+                self.led_power,
+                Message::LEDPowerSelected as fn(_) -> _,
+            ),
+            (tb.led_left, self.led_left, Message::LEDLeftSelected),
+            (tb.led_right, self.led_right, Message::LEDRightSelected),
+        ];
+        for (new, old, message) in leds {
+            if let Some(value) = new.filter(|&value| Some(value) != old) {
+                self.update(message(value));
+            }
+        }
+        if tb.backlight_auto != self.backlight_auto {
+            self.update(Message::BacklightAutoToggled(tb.backlight_auto));
+        }
+        self.autosave = tb.autosave;
+        // fwtb-ab watches the file too and picks these up itself
+        self.backlight = tb.backlight;
+    }
+}
+
+// see Command in fwtb-ab, one per connection
+async fn send_backlight_command(command: &str) -> anyhow::Result<String> {
+    let mut path = dirs::runtime_dir().ok_or_else(|| anyhow::anyhow!("no runtime dir"))?;
+    path.push("fwtb");
+    path.push("fwtb-ab.sock");
+    let exchange = async {
+        let mut stream = UnixStream::connect(path).await?;
+        stream
+            .write_all(format!("{}\n", command).as_bytes())
+            .await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        anyhow::Ok(reply)
+    };
+    tokio::time::timeout(Duration::from_millis(500), exchange).await?
+}
+
+// None if there's no config yet. one that can't be read is moved aside
+// rather than lost, and the note says what was wrong with it
+fn load_config(path: &FilePath, system: &Table) -> (Option<Toolbox>, String) {
+    let Ok(s) = read_to_string(path) else {
+        return (None, String::new());
+    };
+    match parse_config(&s, system) {
+        Ok(tb) => {
+            let note = file_note(path, &tb);
+            (Some(tb), note)
+        }
+        Err(e) => {
+            let backup = path.with_extension("toml.bak");
+            let note = match rename(path, &backup) {
+                Ok(()) => format!(
+                    "Couldn't read {}, moved it to {}: {}",
+                    path.display(),
+                    backup.display(),
+                    e
+                ),
+                Err(_) => format!("Couldn't read {}: {}", path.display(), e),
+            };
+            eprintln!("{}", note);
+            (None, note)
+        }
+    }
+}
+
+fn parse_config(s: &str, system: &Table) -> anyhow::Result<Toolbox> {
+    parse_settings(toml_edit::easy::from_str(s)?, system)
+}
+
+// from fwtb.toml or a bundle, brought up to date. anything that isn't set
+// comes from system, see system_config
+fn parse_settings(mut table: Table, system: &Table) -> anyhow::Result<Toolbox> {
+    let mut from_system = Table::new();
+    for (key, value) in system {
+        if key != "version" && !table.contains_key(key) {
+            table.insert(key.clone(), value.clone());
+            from_system.insert(key.clone(), value.clone());
+        }
+    }
+    // from before there were versions if it doesn't say
+    let version = match table.get("version") {
+        Some(version) => version
+            .as_integer()
+            .ok_or_else(|| anyhow::anyhow!("version isn't a number"))?,
+        None => 0,
+    };
+    if version > CONFIG_VERSION {
+        anyhow::bail!("it's from a newer version of fwtb ({})", version);
+    }
+    for from in version..CONFIG_VERSION {
+        migrate(&mut table, from)?;
+    }
+    table.insert(String::from("version"), Value::Integer(CONFIG_VERSION));
+    // likely misspelled, and otherwise quietly the default instead
+    let defaults = to_table(&Toolbox::default())?;
+    let unknown: Vec<&str> = table
+        .keys()
+        .filter(|key| !defaults.contains_key(*key) && !from_system.contains_key(*key))
+        .map(String::as_str)
+        .collect();
+    let note = match unknown.len() {
+        0 => String::new(),
+        1 => format!("Unknown setting {}", unknown[0]),
+        _ => format!("Unknown settings {}", unknown.join(", ")),
+    };
+    // and the rest from ours, so a key left out isn't the whole file rejected
+    for (key, value) in defaults {
+        table.entry(key).or_insert(value);
+    }
+    let mut tb = Value::Table(table).try_into::<Toolbox>()?;
+    tb.from_system = from_system;
+    tb.config_note = note;
+    Ok(tb)
+}
+
+// what parse_settings noticed, and in which file
+fn file_note(path: &FilePath, tb: &Toolbox) -> String {
+    if tb.config_note.is_empty() {
+        return String::new();
+    }
+    format!("{} in {}, ignored", tb.config_note, path.display())
+}
+
+// as it'd be in fwtb.toml
+fn to_table(tb: &Toolbox) -> anyhow::Result<Table> {
+    match Value::try_from(tb)? {
+        Value::Table(table) => Ok(table),
+        _ => anyhow::bail!("settings didn't make a table"),
+    }
+}
+
+// updates what's already there in place, so comments, ordering and anything
+// we don't know about are kept
+fn save_config(path: &FilePath, tb: &Toolbox) -> anyhow::Result<()> {
+    // so changing /etc/fwtb/config.toml still changes them
+    let settings: Table = to_table(tb)?
+        .into_iter()
+        .filter(|(key, value)| tb.from_system.get(key) != Some(value))
+        .collect();
+    let new: Document = toml_edit::easy::to_string(&settings)?.parse()?;
+    let mut doc = match read_to_string(path) {
+        // older versions marked it, but it's fine to edit now
+        Ok(s) => s
+            .replacen("# Generated file, DO NOT EDIT!\n", "", 1)
+            .parse()?,
+        Err(_) => Document::new(),
+    };
+    merge_config(doc.as_table_mut(), new.as_table());
+    write(path, doc.to_string())?;
+    Ok(())
+}
+
+// a key at a time, and into tables, so only what's changed is taken
+fn take_changes(ours: &mut Table, saved: &Table, new: &Table) {
+    for (key, value) in new {
+        if let (Some(Value::Table(ours)), Some(Value::Table(saved)), Value::Table(new)) =
+            (ours.get_mut(key), saved.get(key), value)
+        {
+            take_changes(ours, saved, new);
+        } else if saved.get(key) != Some(value) {
+            ours.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+// takes the layout from one version to the next
+fn migrate(table: &mut Table, from: i64) -> anyhow::Result<()> {
+    match from {
+        // unversioned, from before settings were added, so fill in what's missing
+        0 => {
+            for (key, value) in to_table(&Toolbox::default())? {
+                table.entry(key).or_insert(value);
+            }
+        }
+        // autosave was added, off as saving was before
+        1 => {
+            table
+                .entry(String::from("autosave"))
+                .or_insert(Value::Boolean(false));
+        }
+        _ => anyhow::bail!("no migration from version {}", from),
+    }
+    Ok(())
+}
+
+// everything fwtb keeps, in one file to take to another laptop
+#[derive(Deserialize, Serialize)]
+struct Bundle {
+    // what it was exported from, to tell what might not fit here
+    model: String,
+    // the curve's brightness is in steps of the panel it was learned on
+    max_brightness: Option<u32>,
+    #[serde(default)]
+    curve: Vec<(f32, f32)>,
+    settings: Table,
+}
+
+// [backlight] keys naming the laptop's devices, or fit to its sensor
+const MODEL_KEYS: [&str; 7] = [
+    "sysfs_root",
+    "proc_root",
+    "device",
+    "sensor",
+    "keyboard_device",
+    "monitors",
+    "max_lux",
+];
+
+fn model() -> String {
+    read_to_string("/sys/class/dmi/id/product_name")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn export_bundle(path: &FilePath, tb: &Toolbox) -> anyhow::Result<()> {
+    let settings = to_table(tb)?;
+    let bundle = Bundle {
+        model: model(),
+        max_brightness: tb.max_brightness,
+        curve: tb.curve.clone(),
+        settings,
+    };
+    // laid out like fwtb.toml rather than all on one line
+    let new: Document = toml_edit::easy::to_string(&bundle)?.parse()?;
+    let mut doc = Document::new();
+    merge_config(doc.as_table_mut(), new.as_table());
+    write(path, doc.to_string())?;
+    Ok(())
+}
+
+// fits one to this laptop, and says what importing it would change
+fn preview_bundle(path: &FilePath, tb: &Toolbox) -> anyhow::Result<(Bundle, Vec<String>)> {
+    let mut bundle: Bundle = toml_edit::easy::from_str(&read_to_string(path)?)?;
+    let mut changes = Vec::new();
+
+    let parsed = parse_settings(bundle.settings, &system_config())?;
+    if !parsed.config_note.is_empty() {
+        changes.push(format!("{} in the bundle, skipping", parsed.config_note));
+    }
+    let mut settings = to_table(&parsed)?;
+    // keep what's set up for this one's devices
+    if bundle.model != model() {
+        if let Some(Value::Table(backlight)) = settings.get_mut("backlight") {
+            for key in MODEL_KEYS {
+                let ours = tb.backlight.get(key).cloned();
+                let theirs = match ours.clone() {
+                    Some(value) => backlight.insert(key.to_string(), value),
+                    None => backlight.remove(key),
+                };
+                if theirs.is_some() && theirs != ours {
+                    changes.push(format!(
+                        "Skipping backlight.{}, it's for a {}",
+                        key, bundle.model
+                    ));
+                }
+            }
+        }
+    }
+    let current = to_table(tb)?;
+    diff_settings("", &current, &settings, &mut changes);
+    bundle.settings = settings;
+
+    if bundle.curve.len() >= 2 {
+        match (bundle.max_brightness, tb.max_brightness) {
+            (Some(theirs), Some(ours)) if theirs != ours && theirs > 0 => {
+                let scale = ours as f32 / theirs as f32;
+                for (_, value) in &mut bundle.curve {
+                    *value = (*value * scale).round();
+                }
+                changes.push(format!(
+                    "Scaling the curve from {} to {} brightness steps",
+                    theirs, ours
+                ));
+            }
+            (None, _) => changes.push(String::from(
+                "The bundle doesn't say what panel its curve was learned on, \
+                 it's applied unscaled",
+            )),
+            (_, None) => changes.push(String::from(
+                "fwtb-ab isn't running to say this panel's brightness range, \
+                 the curve's applied unscaled",
+            )),
+            _ => {}
+        }
+        if bundle.curve != tb.curve {
+            changes.push(format!(
+                "Learned curve: {} keys, replacing {}",
+                bundle.curve.len(),
+                tb.curve.len()
+            ));
+        }
+    } else {
+        bundle.curve.clear();
+    }
+    Ok((bundle, changes))
+}
+
+fn diff_settings(prefix: &str, old: &Table, new: &Table, changes: &mut Vec<String>) {
+    let show = |value: Option<&Value>| match value {
+        Some(value) => value.to_string(),
+        None => String::from("unset"),
+    };
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for key in keys {
+        match (old.get(key), new.get(key)) {
+            (Some(Value::Table(old)), Some(Value::Table(new))) => {
+                diff_settings(&format!("{}{}.", prefix, key), old, new, changes)
+            }
+            (old, new) if old != new => {
+                changes.push(format!("{}{}: {} → {}", prefix, key, show(old), show(new)))
+            }
+            _ => {}
+        }
+    }
+}
+
+// written by fwtb-ab, see SavedCurve and Status there
+#[derive(Deserialize, Serialize)]
+struct SavedCurve {
+    keys: Vec<(f32, f32)>,
+}
+
+#[derive(Deserialize)]
+struct BacklightStatus {
+    lux: f32,
+    max_brightness: u32,
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    offset: f64,
+}
+
+// what fwtbd.service applies at boot, the same layout as fwtb.toml
+fn system_config() -> Table {
+    let path = "/etc/fwtb/config.toml";
+    let Ok(s) = read_to_string(path) else {
+        return Table::new();
+    };
+    toml_edit::easy::from_str(&s).unwrap_or_else(|e| {
+        eprintln!("couldn't read {}: {}", path, e);
+        Table::new()
+    })
+}
+
+fn watch_config(path: PathBuf) -> Subscription<Message> {
+    iced::subscription::unfold("config", None, move |watch: Option<ConfigWatch>| {
+        let path = path.clone();
+        async move {
+            let watch = match watch {
+                Some(watch) => Ok(watch),
+                None => ConfigWatch::new(&path),
+            };
+            let mut watch = match watch {
+                Ok(watch) => watch,
+                Err(e) => {
+                    eprintln!("can't watch {} for changes: {}", path.display(), e);
+                    std::future::pending().await
+                }
+            };
+            match watch.changed().await {
+                Ok(()) => {
+                    // let whatever's writing it finish
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    (Some(Message::ConfigChanged), Some(watch))
+                }
+                Err(e) => {
+                    eprintln!("stopped watching {}: {}", path.display(), e);
+                    std::future::pending().await
+                }
+            }
+        }
+    })
+}
+
+fn curve_path() -> PathBuf {
+    let mut path = dirs::state_dir().unwrap();
+    path.push("fwtb");
+    path.push("curve.toml");
+    path
+}
+
+fn read_curve(path: &FilePath) -> anyhow::Result<Vec<(f32, f32)>> {
+    let saved: SavedCurve = toml_edit::easy::from_str(&read_to_string(path)?)?;
+    if saved.keys.len() < 2 {
+        anyhow::bail!("a curve needs at least two keys");
+    }
+    Ok(saved.keys)
+}
+
+fn write_curve(path: &FilePath, keys: &[(f32, f32)]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let keys = keys.to_vec();
+    write(path, toml_edit::easy::to_string(&SavedCurve { keys })?)?;
+    Ok(())
+}
+
+const PLOT_PADDING: f32 = 8.;
+const KEY_RADIUS: f32 = 4.;
+
+// lux on x, brightness on y
+struct CurvePlot<'a> {
+    keys: &'a [(f32, f32)],
+    revision: u64,
+    lux: Option<f32>,
+    max_brightness: Option<u32>,
+    // set in perceptual mode, plots log lux and gamma encoded brightness
+    gamma: Option<f32>,
+}
+
+impl CurvePlot<'_> {
+    fn range(&self) -> (f32, f32) {
+        let t = self.keys.last().map_or(1., |key| key.0).max(1.);
+        let v = self
+            .keys
+            .iter()
+            .map(|key| key.1)
+            .fold(self.max_brightness.unwrap_or(1) as f32, f32::max);
+        (t, v)
+    }
+
+    fn to_screen(&self, size: Size, (t, v): (f32, f32)) -> Point {
+        let (t_max, v_max) = self.range();
+        let (x, y) = match self.gamma {
+            Some(gamma) => (
+                t.max(0.).ln_1p() / t_max.ln_1p(),
+                (v / v_max).max(0.).powf(gamma.recip()),
+            ),
+            None => (t / t_max, v / v_max),
+        };
+        let width = size.width - 2. * PLOT_PADDING;
+        let height = size.height - 2. * PLOT_PADDING;
+        Point::new(PLOT_PADDING + x * width, PLOT_PADDING + height - y * height)
+    }
+
+    fn to_curve(&self, size: Size, p: Point) -> (f32, f32) {
+        let (t_max, v_max) = self.range();
+        let width = size.width - 2. * PLOT_PADDING;
+        let height = size.height - 2. * PLOT_PADDING;
+        let x = ((p.x - PLOT_PADDING) / width).clamp(0., 1.);
+        let y = ((PLOT_PADDING + height - p.y) / height).clamp(0., 1.);
+        match self.gamma {
+            Some(gamma) => ((x * t_max.ln_1p()).exp_m1(), y.powf(gamma) * v_max),
+            None => (x * t_max, y * v_max),
+        }
+    }
+
+    // keys have to stay in order and the curve can't go downhill
+    fn clamp(&self, i: usize, (t, v): (f32, f32)) -> (f32, f32) {
+        let (_, v_max) = self.range();
+        let last = self.keys.len() - 1;
+        let t = if i == 0 || i == last {
+            self.keys[i].0
+        } else {
+            t.max(self.keys[i - 1].0).min(self.keys[i + 1].0)
+        };
+        let lo = if i == 0 { 0. } else { self.keys[i - 1].1 };
+        let hi = if i == last { v_max } else { self.keys[i + 1].1 };
+        (t, v.max(lo).min(hi).round())
+    }
+
+    fn key_at(&self, size: Size, p: Point) -> Option<usize> {
+        self.keys
+            .iter()
+            .position(|&key| self.to_screen(size, key).distance(p) <= KEY_RADIUS * 2.)
+    }
+}
+
+impl canvas::Program<Message> for CurvePlot<'_> {
+    // curve revision and key being dragged
+    type State = Option<(u64, usize)>;
+
+    fn update(
+        &self,
+        state: &mut Option<(u64, usize)>,
+        event: canvas::Event,
+        bounds: Rectangle,
+        cursor: Cursor,
+    ) -> (event::Status, Option<Message>) {
+        let position = match cursor.position() {
+            Some(p) => Point::new(p.x - bounds.x, p.y - bounds.y),
+            None => return (event::Status::Ignored, None),
+        };
+
+        match event {
+            canvas::Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Left))
+                if cursor.is_over(&bounds) =>
+            {
+                *state = self
+                    .key_at(bounds.size(), position)
+                    .map(|i| (self.revision, i));
+                if state.is_some() {
+                    return (event::Status::Captured, None);
+                }
+            }
+            canvas::Event::Mouse(mouse::Event::CursorMoved { .. }) => match *state {
+                // the curve was reloaded or reset since it was grabbed
+                Some((revision, _)) if revision != self.revision => *state = None,
+                Some((revision, i)) if i < self.keys.len() => {
+                    let (t, v) = self.clamp(i, self.to_curve(bounds.size(), position));
+                    return (
+                        event::Status::Captured,
+                        Some(Message::CurveKeyDragged(revision, i, t, v)),
+                    );
+                }
+                _ => {}
+            },
+            canvas::Event::Mouse(mouse::Event::ButtonReleased(mouse::Button::Left))
+                if state.take().is_some() =>
+            {
+                return (event::Status::Captured, Some(Message::CurveKeyReleased));
+            }
+            _ => {}
+        }
+        (event::Status::Ignored, None)
+    }
+
+    fn draw(
+        &self,
+        _state: &Option<(u64, usize)>,
+        _theme: &Theme,
+        bounds: Rectangle,
+        _cursor: Cursor,
+    ) -> Vec<Geometry> {
+        let size = bounds.size();
+        let mut frame = Frame::new(size);
+        let grey = Color::from([0.5, 0.5, 0.5]);
+        let accent = Color::from([0.4, 0.6, 1.0]);
+
+        let axes = Path::new(|p| {
+            p.move_to(Point::new(PLOT_PADDING, PLOT_PADDING));
+            p.line_to(Point::new(PLOT_PADDING, size.height - PLOT_PADDING));
+            p.line_to(Point::new(
+                size.width - PLOT_PADDING,
+                size.height - PLOT_PADDING,
+            ));
+        });
+        frame.stroke(&axes, Stroke::default().with_color(grey).with_width(1.));
+
+        if let Some(lux) = self.lux {
+            let (t_max, _) = self.range();
+            let x = self.to_screen(size, (lux.min(t_max), 0.)).x;
+            let marker = Path::line(
+                Point::new(x, PLOT_PADDING),
+                Point::new(x, size.height - PLOT_PADDING),
+            );
+            frame.stroke(
+                &marker,
+                Stroke::default().with_color(Color::WHITE).with_width(1.),
+            );
+        }
+
+        if let Some((&first, rest)) = self.keys.split_first() {
+            let line = Path::new(|p| {
+                p.move_to(self.to_screen(size, first));
+                for &key in rest {
+                    p.line_to(self.to_screen(size, key));
+                }
+            });
+            frame.stroke(&line, Stroke::default().with_color(accent).with_width(2.));
+        }
+
+        for &key in self.keys {
+            frame.fill(&Path::circle(self.to_screen(size, key), KEY_RADIUS), accent);
+        }
+
+        vec![frame.into_geometry()]
+    }
+
+    fn mouse_interaction(
+        &self,
+        state: &Option<(u64, usize)>,
+        bounds: Rectangle,
+        cursor: Cursor,
+    ) -> mouse::Interaction {
+        if state.is_some() {
+            return mouse::Interaction::Grabbing;
+        }
+        match cursor.position_in(&bounds) {
+            Some(p) if self.key_at(bounds.size(), p).is_some() => mouse::Interaction::Grab,
+            _ => mouse::Interaction::default(),
+        }
     }
 }
 
@@ -451,3 +1526,112 @@ impl std::fmt::Display for LedColor {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_keys_come_from_the_defaults() {
+        let tb = parse_config("version = 2\nbattery_limit = 80\n", &Table::new()).unwrap();
+        let defaults = Toolbox::default();
+        assert_eq!(tb.battery_limit, 80);
+        assert_eq!(tb.fan_duty, defaults.fan_duty);
+        assert_eq!(tb.autosave, defaults.autosave);
+
+        // /etc/fwtb/config.toml fills in ahead of ours, but not over the file
+        let system = toml_edit::easy::from_str("battery_limit = 60\nfan_duty = 30\n").unwrap();
+        let tb = parse_config("version = 2\nbattery_limit = 80\n", &system).unwrap();
+        assert_eq!((tb.battery_limit, tb.fan_duty), (80, 30));
+        assert_eq!(tb.from_system.keys().collect::<Vec<_>>(), ["fan_duty"]);
+    }
+
+    #[test]
+    fn unknown_keys_are_noted() {
+        let tb = parse_config("version = 2\nbatery_limit = 80\n", &Table::new()).unwrap();
+        assert_eq!(tb.battery_limit, Toolbox::default().battery_limit);
+        assert_eq!(tb.config_note, "Unknown setting batery_limit");
+        let path = FilePath::new("fwtb.toml");
+        assert_eq!(
+            file_note(path, &tb),
+            "Unknown setting batery_limit in fwtb.toml, ignored"
+        );
+        assert_eq!(
+            file_note(path, &parse_config("", &Table::new()).unwrap()),
+            ""
+        );
+    }
+
+    #[test]
+    fn old_configs_are_migrated() {
+        let tb = parse_config(
+            "version = 1\nbattery_limit = 80\nfan_duty = 30\n",
+            &Table::new(),
+        )
+        .unwrap();
+        assert_eq!((tb.version, tb.fan_duty), (CONFIG_VERSION, 30));
+        // saving wasn't automatic before there was a switch for it
+        assert!(!tb.autosave);
+    }
+
+    #[test]
+    fn system_settings_stay_out_of_fwtb_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fwtb.toml");
+        std::fs::write(&path, "version = 2\n# ours\nfan_duty = 30\n").unwrap();
+        let system = toml_edit::easy::from_str("battery_limit = 80\nfan_auto = false\n").unwrap();
+        let mut tb = parse_config(&read_to_string(&path).unwrap(), &system).unwrap();
+        assert_eq!((tb.battery_limit, tb.fan_auto), (80, false));
+        tb.fan_auto = true;
+        save_config(&path, &tb).unwrap();
+
+        let saved = read_to_string(&path).unwrap();
+        assert!(saved.contains("# ours\nfan_duty = 30\n"), "{}", saved);
+        assert!(!saved.contains("battery_limit"), "{}", saved);
+        // changed here, so it's ours now
+        assert!(saved.contains("fan_auto = true"), "{}", saved);
+    }
+
+    #[test]
+    fn one_setting_is_saved_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fwtb.toml");
+        std::fs::write(
+            &path,
+            "version = 2\n# mine\nbacklight_auto = false # for now\n",
+        )
+        .unwrap();
+        save_setting(&path, "backlight_auto", true).unwrap();
+        assert_eq!(
+            read_to_string(&path).unwrap(),
+            "version = 2\n# mine\nbacklight_auto = true # for now\n"
+        );
+    }
+
+    #[test]
+    fn reloading_keeps_unsaved_changes() {
+        let system = Table::new();
+        let mut tb = parse_config("version = 2\n[backlight]\nmax_lux = 100\n", &system).unwrap();
+        tb.saved = tb.settings();
+        tb.fan_duty = 30;
+        tb.backlight
+            .insert(String::from("window"), Value::Integer(8));
+        // then the tray or an editor changes some others
+        let disk = parse_config(
+            "version = 2\nbattery_limit = 80\n[backlight]\nmax_lux = 200\n",
+            &system,
+        )
+        .unwrap();
+        let merged = tb.with_changes(&disk).unwrap();
+        assert_eq!((merged.battery_limit, merged.fan_duty), (80, 30));
+        assert_eq!(merged.backlight.get("max_lux"), Some(&Value::Integer(200)));
+        assert_eq!(merged.backlight.get("window"), Some(&Value::Integer(8)));
+    }
+
+    #[test]
+    fn unknown_versions_are_errors() {
+        let newer = format!("version = {}\n", CONFIG_VERSION + 1);
+        assert!(parse_config(&newer, &Table::new()).is_err());
+        assert!(migrate(&mut Table::new(), CONFIG_VERSION).is_err());
+    }
+}